@@ -1,7 +1,12 @@
 sectalign off
 
 ; stage 1 is sector 0, loaded at 0x7C00
+%ifdef PXE
+; PXE loads the whole image at 0x7C00
+%include "stage1_pxe.asm"
+%else
 %include "stage1.asm"
+%endif
 
 ; GPT area from sector 1 to 33, loaded at 0x7E00
 times (33*512) db 0
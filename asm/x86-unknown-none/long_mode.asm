@@ -4,6 +4,7 @@ USE32
 long_mode:
 .func: dq 0
 .page_table: dd 0
+.la57: dd 0
 
 .entry:
     ; disable interrupts
@@ -17,6 +18,12 @@ long_mode:
     ; enable FXSAVE/FXRSTOR, Page Global, Page Address Extension, and Page Size Extension
     mov eax, cr4
     or eax, 1 << 9 | 1 << 7 | 1 << 5 | 1 << 4
+
+    ; enable five-level paging if requested, page_table is a PML5 then
+    cmp dword [.la57], 0
+    je .cr4
+    or eax, 1 << 12
+.cr4:
     mov cr4, eax
 
     ; load long mode GDT
@@ -0,0 +1,94 @@
+ORG 0x7C00
+SECTION .text
+USE16
+
+; stage 1 when loaded by PXE, which loads the whole image at 0x7C00
+;  ES:BX points to the PXENV+ structure
+;  SS:SP+4 points to the !PXE structure
+stage1:
+    cli
+
+    ; save !PXE pointer from the PXE stack
+    mov bp, sp
+    mov eax, [ss:bp + 4]
+
+    ; save PXENV+ segment
+    mov cx, es
+
+    ; initialize segment registers
+    xor dx, dx
+    mov ds, dx
+    mov es, dx
+    mov ss, dx
+
+    ; initialize stack
+    mov sp, 0x7C00
+    sti
+
+    mov [pxe.bang], eax
+    mov [pxe.pxenv], bx
+    mov [pxe.pxenv + 2], cx
+
+    ; initialize CS
+    push dx
+    push word .set_cs
+    retf
+
+.set_cs:
+
+    mov si, stage_msg
+    call print
+    mov al, '1'
+    call print_char
+    call print_line
+
+    ; prefer the !PXE entry point, which is not present before PXE 2.1
+    lfs si, [pxe.bang]
+    cmp dword [fs:si], '!PXE'
+    jne .pxenv
+    mov eax, [fs:si + 0x10] ; EntryPointSP
+    jmp .found
+
+.pxenv:
+    lfs si, [pxe.pxenv]
+    cmp dword [fs:si], 'PXEN'
+    jne error
+    cmp word [fs:si + 4], 'V+'
+    jne error
+    mov eax, [fs:si + 0x0A] ; RMEntry
+
+.found:
+    mov [pxe.entry], eax
+
+    ; the rest of the image was loaded with stage 1
+    mov si, stage_msg
+    call print
+    mov al, '2'
+    call print_char
+    call print_line
+
+    jmp stage2.entry
+
+error:
+    mov si, error_msg
+    call print
+    call print_line
+.halt:
+    cli
+    hlt
+    jmp .halt
+
+%include "print.asm"
+
+stage_msg: db "Stage ",0
+error_msg: db "PXE not found",0
+
+; there is no boot disk, the boot loader uses the PXE API instead
+disk: db 0
+
+pxe:
+.pxenv: dd 0
+.bang: dd 0
+.entry: dd 0
+
+times 512-($-$$) db 0
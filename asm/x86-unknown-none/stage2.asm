@@ -26,6 +26,14 @@ stage3.entry:
     mov esp, 0x70000
 
     ; push arguments
+%ifdef PXE
+    mov eax, thunk.pxe
+%else
+    xor eax, eax
+%endif
+    push eax
+    mov eax, thunk.chainload
+    push eax
     mov eax, thunk.int16
     push eax
     mov eax, thunk.int15
@@ -72,11 +80,16 @@ kernel:
     mov eax, [esp + 24]
     mov [.args], eax
 
-    ; long_mode: usize
+    ; long_mode: usize, 0 for 32-bit, 1 for four-level and 2 for five-level paging
     mov eax, [esp + 28]
     test eax, eax
     jz .inner32
 
+    cmp eax, 2
+    jne .enter_long_mode
+    mov dword [long_mode.la57], 1
+
+.enter_long_mode:
     mov eax, .inner64
     mov [long_mode.func], eax
     jmp long_mode.entry
@@ -18,6 +18,16 @@ thunk:
     mov dword [.func], .int16_real
     jmp .enter
 
+.chainload:
+    mov dword [.func], .chainload_real
+    jmp .enter
+
+%ifdef PXE
+.pxe:
+    mov dword [.func], .pxe_real
+    jmp .enter
+%endif
+
 .func: dd 0
 .esp: dd 0
 .cr0: dd 0
@@ -77,6 +87,23 @@ USE16
     int 0x16
     ret
 
+; jump to the boot sector at 0x7C00 with DL set to the drive and DS:SI pointing to the
+; partition entry, this does not return
+.chainload_real:
+    jmp 0:0x7C00
+
+%ifdef PXE
+; BX is the opcode and ES:DI the parameter structure. These are passed in registers for the
+; PXENV+ entry point and on the stack for the !PXE entry point.
+.pxe_real:
+    push es
+    push di
+    push bx
+    call far [pxe.entry]
+    add sp, 6
+    ret
+%endif
+
 .pm16:
     ; set segment selectors to protected mode 16-bit
     mov eax, gdt.pm16_data
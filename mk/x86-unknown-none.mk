@@ -34,6 +34,10 @@ $(BUILD)/%.elf: $(BUILD)/lib%.a $(SOURCE)/linkers/$(TARGET).ld
 $(BUILD)/%.bin: $(BUILD)/%.elf $(shell find $(SOURCE)/asm/$(TARGET) -type f)
 	nasm -f bin -o "$@" -l "$@.lst" -D STAGE3="$<" -i"$(SOURCE)/asm/$(TARGET)/" "$(SOURCE)/asm/$(TARGET)/bootloader.asm"
 
+# Image for PXE, which loads the whole file at 0x7C00
+$(BUILD)/%.pxe: $(BUILD)/%.elf $(shell find $(SOURCE)/asm/$(TARGET) -type f)
+	nasm -f bin -o "$@" -l "$@.lst" -D STAGE3="$<" -D PXE -i"$(SOURCE)/asm/$(TARGET)/" "$(SOURCE)/asm/$(TARGET)/bootloader.asm"
+
 $(BUILD)/harddrive.bin: $(BUILD)/bootloader.bin $(BUILD)/filesystem.bin
 	rm -f "$@.partial"
 	fallocate -l 256MiB "$@.partial"
@@ -57,3 +61,26 @@ qemu: $(BUILD)/harddrive.bin
 		-enable-kvm \
 		-cpu host \
 		-drive file="$<",format=raw
+
+$(BUILD)/tftp: $(BUILD)/bootloader-live.pxe $(BUILD)/harddrive.bin
+	rm -rf "$@.partial"
+	mkdir -p "$@.partial"
+	cp "$(BUILD)/bootloader-live.pxe" "$@.partial/bootloader.pxe"
+	cp "$(BUILD)/harddrive.bin" "$@.partial/redox-live.iso"
+	rm -rf "$@"
+	mv "$@.partial" "$@"
+
+qemu-pxe: $(BUILD)/tftp
+	$(QEMU) \
+		-d cpu_reset \
+		-no-reboot \
+		-smp 4 -m 2048 \
+		-chardev stdio,id=debug,signal=off,mux=on \
+		-serial chardev:debug \
+		-mon chardev=debug \
+		-machine q35 \
+		-netdev user,id=net0,tftp="$<",bootfile=bootloader.pxe \
+		-device e1000,netdev=net0 \
+		-enable-kvm \
+		-cpu host \
+		-boot n
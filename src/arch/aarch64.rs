@@ -1,5 +1,6 @@
+use crate::arch::phys_map_size;
 use crate::area_add;
-use crate::os::{Os, OsMemoryEntry, OsMemoryKind, dtb::is_in_dev_mem_region};
+use crate::os::{Os, OsMemoryEntry, OsMemoryKind, cacheable_regions, dtb::DEV_MEM_AREA};
 use core::slice;
 
 pub(crate) const PF_PRESENT: u64 = 1 << 0;
@@ -8,13 +9,18 @@ pub(crate) const PF_OUTER_SHAREABLE: u64 = 0b01 << 8;
 pub(crate) const PF_INNER_SHAREABLE: u64 = 0b11 << 8;
 pub(crate) const PF_ACCESS: u64 = 1 << 10;
 
+// Memory attributes, indexes into MAIR_EL1 as set in kernel_entry
 pub(crate) const PF_DEV: u64 = PF_OUTER_SHAREABLE | 2 << 2;
 pub(crate) const PF_RAM: u64 = PF_INNER_SHAREABLE;
+// Normal non-cacheable memory, allowing writes to be combined
+pub(crate) const PF_WC: u64 = PF_OUTER_SHAREABLE | 1 << 2;
 
 pub(crate) const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 pub(crate) const PAGE_ENTRIES: usize = 512;
 const PAGE_SIZE: usize = 4096;
 pub(crate) const PHYS_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+// The linear map uses kernel L0 entries up to the one mapping the kernel
+pub(crate) const PHYS_MAP_LIMIT: u64 = 254 * 0x80_0000_0000;
 
 unsafe fn paging_allocate(os: &impl Os) -> Option<&'static mut [u64]> {
     unsafe {
@@ -32,24 +38,110 @@ unsafe fn paging_allocate(os: &impl Os) -> Option<&'static mut [u64]> {
     }
 }
 
+/// Get the table an entry points to, creating it if the entry is empty, or splitting a block into
+/// smaller blocks or pages with the same attributes
+unsafe fn paging_next(
+    os: &impl Os,
+    table: &mut [u64],
+    i: usize,
+    level: u32,
+) -> Option<&'static mut [u64]> {
+    unsafe {
+        let entry = table[i];
+        if entry & PF_PRESENT != 0 && entry & PF_TABLE != 0 {
+            return Some(slice::from_raw_parts_mut(
+                (entry & ENTRY_ADDRESS_MASK) as *mut u64,
+                PAGE_ENTRIES,
+            ));
+        }
+
+        let next = paging_allocate(os)?;
+        if entry & PF_PRESENT != 0 {
+            let next_size = 1 << (39 - 9 * (level + 1));
+            // Pages have the same bit set as table entries
+            let page = if level + 1 == 3 { PF_TABLE } else { 0 };
+            for (next_i, next_entry) in next.iter_mut().enumerate() {
+                *next_entry = ((entry & ENTRY_ADDRESS_MASK) + next_i as u64 * next_size)
+                    | (entry & !ENTRY_ADDRESS_MASK)
+                    | page;
+            }
+        }
+        table[i] = next.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
+        Some(next)
+    }
+}
+
+/// Map physical memory at PHYS_OFFSET with the given attributes, using the largest blocks that
+/// fit and replacing or splitting existing mappings. The range is extended to whole pages.
+unsafe fn map_phys(os: &impl Os, l0: &mut [u64], phys: u64, size: u64, attrs: u64) -> Option<()> {
+    unsafe {
+        let mut addr = phys & !(PAGE_SIZE as u64 - 1);
+        let end = (phys + size).next_multiple_of(PAGE_SIZE as u64);
+        while addr < end {
+            let l0_i = (addr >> 39) as usize + PAGE_ENTRIES / 2;
+            let mut table = paging_next(os, l0, l0_i, 0)?;
+            let mut level = 1;
+            loop {
+                let shift = 39 - 9 * level;
+                let block_size = 1u64 << shift;
+                let i = (addr >> shift) as usize % PAGE_ENTRIES;
+                if level == 3 {
+                    table[i] = addr | PF_ACCESS | attrs | PF_TABLE | PF_PRESENT;
+                } else if addr % block_size == 0 && addr + block_size <= end {
+                    table[i] = addr | PF_ACCESS | attrs | PF_PRESENT;
+                } else {
+                    table = paging_next(os, table, i, level)?;
+                    level += 1;
+                    continue;
+                }
+                addr += block_size;
+                break;
+            }
+        }
+        Some(())
+    }
+}
+
 pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) -> Option<usize> {
     unsafe {
         // Create L0
         let l0 = paging_allocate(os)?;
 
         {
-            // Create L1 for identity mapping
-            let l1 = paging_allocate(os)?;
+            // Identity map all physical memory as device memory using 1 GiB pages, also mapping it
+            // at PHYS_OFFSET
+            let phys_size = phys_map_size(os, PHYS_MAP_LIMIT);
+            let mut addr = 0;
+            for l0_i in 0..phys_size.div_ceil(0x80_0000_0000) as usize {
+                // Create L1 for identity mapping
+                let l1 = paging_allocate(os)?;
+
+                // Link user and kernel L0 entries to L1
+                l0[l0_i] = l1.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
+                l0[256 + l0_i] = l1.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
+
+                for l1_i in 0..l1.len() {
+                    if addr >= phys_size {
+                        break;
+                    }
 
-            // Link first user and first kernel L0 entry to L1
-            l0[0] = l1.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
-            l0[256] = l1.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
+                    l1[l1_i] = addr | PF_ACCESS | PF_DEV | PF_PRESENT;
+                    addr += 0x4000_0000;
+                }
+            }
 
-            // Identity map 8 GiB using 1 GiB pages
-            for l1_i in 0..8 {
-                let addr = l1_i as u64 * 0x4000_0000;
-                //TODO: is PF_RAM okay?
-                l1[l1_i] = addr | PF_ACCESS | PF_DEV | PF_PRESENT;
+            // Memory that the firmware maps as cacheable is RAM, unless the device tree says
+            // otherwise
+            for (base, size) in cacheable_regions() {
+                if base < phys_size {
+                    map_phys(os, l0, base, size.min(phys_size - base), PF_RAM)?;
+                }
+            }
+            #[allow(static_mut_refs)]
+            for &(base, size) in DEV_MEM_AREA.iter() {
+                if (base as u64) < phys_size {
+                    map_phys(os, l0, base as u64, size as u64, PF_DEV)?;
+                }
             }
         }
 
@@ -97,58 +189,8 @@ pub unsafe fn paging_framebuffer(
     framebuffer_size: u64,
 ) -> Option<u64> {
     unsafe {
-        //TODO: smarter test for framebuffer already mapped
-        if framebuffer_phys + framebuffer_size <= 0x2_0000_0000 {
-            return Some(framebuffer_phys + PHYS_OFFSET);
-        }
-
-        let l0_i = ((framebuffer_phys / 0x80_0000_0000) + 256) as usize;
-        let mut l1_i = ((framebuffer_phys % 0x80_0000_0000) / 0x4000_0000) as usize;
-        let mut l2_i = ((framebuffer_phys % 0x4000_0000) / 0x20_0000) as usize;
-        let mut l3_i = ((framebuffer_phys % 0x20_0000) / (PAGE_SIZE as u64)) as usize;
-        assert_eq!(framebuffer_phys % (PAGE_SIZE as u64), 0);
-
         let l0 = slice::from_raw_parts_mut(page_phys as *mut u64, PAGE_ENTRIES);
-
-        // Create l1 for framebuffer mapping
-        let l1 = if l0[l0_i] == 0 {
-            let l1 = paging_allocate(os)?;
-            l0[l0_i] = l1.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
-            l1
-        } else {
-            slice::from_raw_parts_mut((l0[l0_i] & ENTRY_ADDRESS_MASK) as *mut u64, PAGE_ENTRIES)
-        };
-
-        // Map framebuffer_size at framebuffer offset
-        let mut framebuffer_mapped = 0;
-        while framebuffer_mapped < framebuffer_size && l1_i < l1.len() {
-            let l2 = paging_allocate(os)?;
-            assert_eq!(l1[l1_i], 0);
-            l1[l1_i] = l2.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
-
-            while framebuffer_mapped < framebuffer_size && l2_i < l2.len() {
-                let l3 = paging_allocate(os)?;
-                assert_eq!(l2[l2_i], 0);
-                l2[l2_i] = l3.as_ptr() as u64 | PF_ACCESS | PF_TABLE | PF_PRESENT;
-
-                while framebuffer_mapped < framebuffer_size && l3_i < l3.len() {
-                    let addr = framebuffer_phys + framebuffer_mapped;
-                    assert_eq!(l3[l3_i], 0);
-                    //TODO: is PF_RAM okay?
-                    l3[l3_i] = addr | PF_ACCESS | PF_RAM | PF_TABLE | PF_PRESENT;
-                    framebuffer_mapped += PAGE_SIZE as u64;
-                    l3_i += 1;
-                }
-
-                l2_i += 1;
-                l3_i = 0;
-            }
-
-            l1_i += 1;
-            l2_i = 0;
-        }
-        assert!(framebuffer_mapped >= framebuffer_size);
-
+        map_phys(os, l0, framebuffer_phys, framebuffer_size, PF_WC)?;
         Some(framebuffer_phys + PHYS_OFFSET)
     }
 }
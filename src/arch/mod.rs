@@ -1,3 +1,7 @@
+use core::cmp;
+
+use crate::os::Os;
+
 #[cfg(target_arch = "aarch64")]
 pub use self::aarch64::*;
 
@@ -15,3 +19,18 @@ pub use self::riscv64::*;
 
 #[cfg(target_arch = "riscv64")]
 mod riscv64;
+
+/// Size of the linear map of physical memory, which covers the memory map and at least the first
+/// 4 GiB, where device memory is not always reported. It is rounded up to 1 GiB and limited to
+/// what the page tables can map.
+pub(crate) fn phys_map_size(os: &impl Os, limit: u64) -> u64 {
+    let end = cmp::max(os.memory_end(), 0x1_0000_0000).next_multiple_of(0x4000_0000);
+    if end > limit {
+        log::warn!(
+            "Only mapping {} of {} GiB of physical memory",
+            limit / 0x4000_0000,
+            end / 0x4000_0000
+        );
+    }
+    cmp::min(end, limit)
+}
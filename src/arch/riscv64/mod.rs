@@ -1,4 +1,4 @@
-use core::slice;
+use core::{cmp, slice};
 
 use crate::area_add;
 use crate::os::{Os, OsMemoryEntry, OsMemoryKind};
@@ -22,10 +22,71 @@ const DIRTY: u64 = 1 << 7;
 
 extern crate alloc;
 
-pub(crate) use sv39::PHYS_OFFSET;
-pub(crate) use sv39::SATP_BITS;
-pub(crate) use sv39::paging_create;
-pub(crate) use sv39::paging_physmem as paging_framebuffer;
+// Allocations are limited to what Sv39, the smallest mode, can map
+pub(crate) use sv39::PHYS_MAP_LIMIT;
+
+/// Number of page table levels, 3 for Sv39, 4 for Sv48 and 5 for Sv57, set before paging_create
+pub static mut PAGING_LEVELS: usize = 3;
+
+/// Use the largest paging mode supported by both the harts and the kernel, defaulting to Sv39
+pub fn paging_select(hart_levels: Option<usize>, kernel_levels: Option<usize>) {
+    let levels = match hart_levels {
+        Some(levels) => cmp::min(levels, kernel_levels.unwrap_or(3)),
+        None => {
+            log::warn!("Paging modes of harts not found, using Sv39");
+            3
+        }
+    };
+    log::info!("Using Sv{} paging", 12 + 9 * levels);
+    unsafe {
+        PAGING_LEVELS = levels;
+    }
+}
+
+pub fn paging_levels() -> usize {
+    unsafe { PAGING_LEVELS }
+}
+
+pub fn phys_offset() -> u64 {
+    match paging_levels() {
+        5 => sv57::PHYS_OFFSET,
+        4 => sv48::PHYS_OFFSET,
+        _ => sv39::PHYS_OFFSET,
+    }
+}
+
+pub fn satp_bits() -> usize {
+    match paging_levels() {
+        5 => sv57::SATP_BITS,
+        4 => sv48::SATP_BITS,
+        _ => sv39::SATP_BITS,
+    }
+}
+
+pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) -> Option<usize> {
+    unsafe {
+        match paging_levels() {
+            5 => sv57::paging_create(os, kernel_phys, kernel_size),
+            4 => sv48::paging_create(os, kernel_phys, kernel_size),
+            _ => sv39::paging_create(os, kernel_phys, kernel_size),
+        }
+    }
+}
+
+pub unsafe fn paging_framebuffer(
+    os: &impl Os,
+    page_phys: usize,
+    framebuffer_phys: u64,
+    framebuffer_size: u64,
+) -> Option<u64> {
+    unsafe {
+        match paging_levels() {
+            5 => sv57::paging_physmem(os, page_phys, framebuffer_phys, framebuffer_size),
+            4 => sv48::paging_physmem(os, page_phys, framebuffer_phys, framebuffer_size),
+            _ => sv39::paging_physmem(os, page_phys, framebuffer_phys, framebuffer_size),
+        }
+    }
+}
 
 unsafe fn paging_allocate(os: &impl Os) -> Option<&'static mut [u64]> {
     unsafe {
@@ -43,17 +104,72 @@ unsafe fn paging_allocate(os: &impl Os) -> Option<&'static mut [u64]> {
     }
 }
 
-unsafe fn get_table(os: &impl Os, parent: &mut [u64], index: usize) -> Option<&'static mut [u64]> {
+/// Get the table an entry points to, creating it if the entry is empty or splitting it if it is a
+/// leaf mapping page_size bytes
+unsafe fn get_table(
+    os: &impl Os,
+    parent: &mut [u64],
+    index: usize,
+    page_size: u64,
+) -> Option<&'static mut [u64]> {
     unsafe {
-        if parent[index] == 0 {
-            let table = paging_allocate(os)?;
-            parent[index] = table.as_ptr() as u64 >> 2 | VALID;
-            Some(table)
-        } else {
-            Some(slice::from_raw_parts_mut(
-                (((parent[index] >> 10) & PHYS_MASK as u64) << 12) as *mut u64,
+        let entry = parent[index];
+        if entry & VALID != 0 && entry & RWX == 0 {
+            return Some(slice::from_raw_parts_mut(
+                (((entry >> 10) & PHYS_MASK as u64) << 12) as *mut u64,
                 PAGE_ENTRIES,
-            ))
+            ));
+        }
+
+        let table = paging_allocate(os)?;
+        if entry & VALID != 0 {
+            let next_size = page_size / PAGE_ENTRIES as u64;
+            for (i, next_entry) in table.iter_mut().enumerate() {
+                *next_entry = entry + ((i as u64 * next_size) >> 2);
+            }
+        }
+        parent[index] = table.as_ptr() as u64 >> 2 | VALID;
+        Some(table)
+    }
+}
+
+/// Map physical memory at virt, using 2 MiB pages where both addresses are aligned and 4 KiB pages
+/// elsewhere, and reusing or splitting existing tables. The range is extended to whole pages.
+unsafe fn paging_map(
+    os: &impl Os,
+    root: &mut [u64],
+    levels: usize,
+    virt: u64,
+    phys: u64,
+    size: u64,
+) -> Option<()> {
+    unsafe {
+        let start = phys & !(PAGE_SIZE as u64 - 1);
+        let end = (phys + size).next_multiple_of(PAGE_SIZE as u64);
+        let virt = virt & !(PAGE_SIZE as u64 - 1);
+        let mut addr = start;
+        while addr < end {
+            let page_virt = virt + (addr - start);
+            let mut table = &mut *root;
+            let mut level = levels - 1;
+            loop {
+                let shift = PAGE_SHIFT + level * TABLE_SHIFT;
+                let page_size = 1u64 << shift;
+                let i = (page_virt >> shift) as usize & TABLE_MASK;
+                if level == 0
+                    || (level == 1
+                        && addr % page_size == 0
+                        && page_virt % page_size == 0
+                        && addr + page_size <= end)
+                {
+                    table[i] = (addr >> 2) | RWX | VALID | ACCESSED | DIRTY;
+                    addr += page_size;
+                    break;
+                }
+                table = get_table(os, table, i, page_size)?;
+                level -= 1;
+            }
         }
+        Some(())
     }
 }
@@ -1,12 +1,15 @@
 use core::slice;
 
 use super::*;
+use crate::arch::phys_map_size;
 use crate::os::Os;
 
 // Sv39 scheme
 
 pub(crate) const PHYS_OFFSET: u64 = 0xFFFF_FFC0_0000_0000;
 pub(crate) const SATP_BITS: usize = 8;
+// The linear map uses kernel L2 entries up to the one mapping the kernel
+pub(crate) const PHYS_MAP_LIMIT: u64 = 254 * 0x4000_0000;
 
 pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) -> Option<usize> {
     unsafe {
@@ -14,12 +17,11 @@ pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) ->
         let l2 = paging_allocate(os)?;
 
         {
-            // Create L1 for identity mapping
-            for l2_i in 0..8 {
+            // Identity map all physical memory using 1 GiB pages, also mapping it at PHYS_OFFSET
+            let phys_size = phys_map_size(os, PHYS_MAP_LIMIT);
+            for l2_i in 0..phys_size.div_ceil(0x4000_0000) as usize {
                 let addr = l2_i as u64 * 0x4000_0000;
-                // Identity map 8 GiB using 1GB pages
                 l2[l2_i] = addr >> 2 | RWX | VALID | ACCESSED | DIRTY;
-                // map phys into kernel VAS
                 l2[(PAGE_ENTRIES / 2) + l2_i] = addr >> 2 | RWX | VALID | ACCESSED | DIRTY;
             }
         }
@@ -56,34 +58,12 @@ pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) ->
 
 pub unsafe fn paging_physmem(os: &impl Os, page_phys: usize, phys: u64, size: u64) -> Option<u64> {
     unsafe {
-        if phys + size <= 0x2_0000_0000 {
+        if phys + size <= phys_map_size(os, PHYS_MAP_LIMIT) {
             return Some(phys + PHYS_OFFSET);
         }
 
-        let mut l1_i = (phys as usize >> (PAGE_SHIFT + 2 * TABLE_SHIFT)) + PAGE_ENTRIES / 2;
-        let mut l0_i = (phys as usize >> (PAGE_SHIFT + TABLE_SHIFT)) & TABLE_MASK;
-        assert_eq!(phys & ((1 << (PAGE_SHIFT + TABLE_SHIFT)) - 1), 0);
-
-        let l1 = slice::from_raw_parts_mut(page_phys as *mut u64, PAGE_ENTRIES);
-
-        // Map framebuffer_size at framebuffer offset
-        let mut mapped = 0;
-        while mapped < size && l1_i < l1.len() {
-            let l0 = get_table(os, l1, l1_i)?;
-
-            while mapped < size && l0_i < l0.len() {
-                let addr = phys + mapped;
-                assert_eq!(l0[l0_i], 0);
-                l0[l0_i] = (addr >> 2) | RWX | VALID | ACCESSED | DIRTY;
-                mapped += 1 << (PAGE_SHIFT + TABLE_SHIFT); // Map with 2mb mega-pages
-                l0_i += 1;
-            }
-
-            l1_i += 1;
-            l0_i = 0;
-        }
-
-        assert!(mapped >= size);
+        let root = slice::from_raw_parts_mut(page_phys as *mut u64, PAGE_ENTRIES);
+        paging_map(os, root, 3, phys + PHYS_OFFSET, phys, size)?;
 
         Some(phys + PHYS_OFFSET)
     }
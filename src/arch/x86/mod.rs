@@ -1,8 +1,72 @@
+use core::arch::asm;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, CpuidResult};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, CpuidResult};
+
 use crate::os::Os;
 
 pub(crate) mod x32;
 pub(crate) mod x64;
 
+// The x64 linear map uses all kernel PML4 entries but the last one, which maps the kernel
+pub(crate) const PHYS_MAP_LIMIT: u64 = 255 * 0x80_0000_0000;
+
+/// Use five-level paging for a 64-bit kernel, set before paging_create
+pub static mut LA57: bool = false;
+
+const IA32_PAT: u32 = 0x277;
+// Write-combining memory type in the PAT
+const PAT_TYPE_WC: u32 = 0x01;
+
+/// Read a CPUID leaf, if the processor supports it
+pub(crate) fn cpuid(leaf: u32) -> Option<CpuidResult> {
+    let max_leaf = __cpuid(leaf & 0x8000_0000).eax;
+    if leaf <= max_leaf {
+        Some(__cpuid(leaf))
+    } else {
+        None
+    }
+}
+
+pub fn la57_supported() -> bool {
+    cpuid(7).is_some_and(|res| res.ecx & 1 << 16 != 0)
+}
+
+pub fn pat_supported() -> bool {
+    cpuid(1).is_some_and(|res| res.edx & 1 << 16 != 0)
+}
+
+/// Change PAT entry 4 from write-back to write-combining, which framebuffers are mapped with
+///
+/// This entry is selected by setting only the PAT bit of a page, which firmware does not do, so
+/// existing mappings are not affected.
+pub unsafe fn pat_init() {
+    if !pat_supported() {
+        return;
+    }
+
+    unsafe {
+        let (low, high): (u32, u32);
+        asm!("rdmsr", in("ecx") IA32_PAT, out("eax") low, out("edx") high);
+        let high = (high & !0xFF) | PAT_TYPE_WC;
+        asm!("wrmsr", in("ecx") IA32_PAT, in("eax") low, in("edx") high);
+    }
+}
+
+/// Number of page table levels the kernel is started with
+pub fn paging_levels() -> usize {
+    unsafe {
+        if !crate::KERNEL_64BIT {
+            2
+        } else if LA57 {
+            5
+        } else {
+            4
+        }
+    }
+}
+
 pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) -> Option<usize> {
     unsafe {
         if crate::KERNEL_64BIT {
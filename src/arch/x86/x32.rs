@@ -2,10 +2,17 @@ use crate::area_add;
 use crate::os::{Os, OsMemoryEntry, OsMemoryKind};
 use core::slice;
 
+use super::pat_supported;
+
 const PAGE_ENTRIES: usize = 1024;
 const PAGE_SIZE: usize = 4096;
 pub(crate) const PHYS_OFFSET: u32 = 0x8000_0000;
 
+// Framebuffers are mapped 256 MiB after kernel mapping, but before heap mapping
+const FRAMEBUFFER_BASE: u32 = 0xD000_0000;
+const FRAMEBUFFER_END: u32 = 0xE000_0000;
+static mut FRAMEBUFFER_NEXT: u32 = FRAMEBUFFER_BASE;
+
 unsafe fn paging_allocate(os: &impl Os) -> Option<&'static mut [u32]> {
     unsafe {
         let ptr = os.alloc_zeroed_page_aligned(PAGE_SIZE);
@@ -61,13 +68,27 @@ pub unsafe fn paging_framebuffer(
     framebuffer_size: u64,
 ) -> Option<u64> {
     unsafe {
-        let framebuffer_virt = 0xD000_0000; // 256 MiB after kernel mapping, but before heap mapping
-
         let pd = slice::from_raw_parts_mut(page_phys as *mut u32, PAGE_ENTRIES);
 
-        // Map framebuffer_size at framebuffer offset
+        // Select PAT entry 4, which is write-combining after pat_init
+        let pat_wc = if pat_supported() { 1 << 7 } else { 0 };
+
+        // Map framebuffer_size at framebuffer offset, from the start of its first page
+        let offset = framebuffer_phys % PAGE_SIZE as u64;
+        let framebuffer_phys = framebuffer_phys - offset;
+        let framebuffer_size = framebuffer_size + offset;
+
+        // Each framebuffer gets its own page tables, so start the next one at a 4 MiB boundary
+        let framebuffer_virt = FRAMEBUFFER_NEXT;
+        let size = framebuffer_size.next_multiple_of(0x40_0000);
+        if framebuffer_virt as u64 + size > FRAMEBUFFER_END as u64 {
+            log::warn!("No space to map framebuffer at 0x{:X}", framebuffer_phys);
+            return None;
+        }
+        FRAMEBUFFER_NEXT = (framebuffer_virt as u64 + size) as u32;
+
         let mut framebuffer_mapped = 0;
-        let mut pd_i = framebuffer_virt / 0x40_0000;
+        let mut pd_i = framebuffer_virt as usize / 0x40_0000;
         while framebuffer_mapped < framebuffer_size && pd_i < pd.len() {
             let pt = paging_allocate(os)?;
             pd[pd_i] = pt.as_ptr() as u32 | 1 << 1 | 1;
@@ -76,13 +97,13 @@ pub unsafe fn paging_framebuffer(
             let mut pt_i = 0;
             while framebuffer_mapped < framebuffer_size && pt_i < pt.len() {
                 let addr = framebuffer_phys + framebuffer_mapped;
-                pt[pt_i] = addr as u32 | 1 << 1 | 1;
+                pt[pt_i] = addr as u32 | pat_wc | 1 << 1 | 1;
                 pt_i += 1;
                 framebuffer_mapped += PAGE_SIZE as u64;
             }
         }
         assert!(framebuffer_mapped >= framebuffer_size);
 
-        Some(framebuffer_virt as u64)
+        Some(framebuffer_virt as u64 + offset)
     }
 }
@@ -1,8 +1,11 @@
 use core::slice;
 
+use crate::arch::phys_map_size;
 use crate::area_add;
 use crate::os::{Os, OsMemoryEntry, OsMemoryKind};
 
+use super::{LA57, PHYS_MAP_LIMIT, cpuid, pat_supported};
+
 const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 const PAGE_ENTRIES: usize = 512;
 const PAGE_SIZE: usize = 4096;
@@ -28,6 +31,99 @@ unsafe fn paging_allocate(os: &impl Os) -> Option<&'static mut [u64]> {
 const PRESENT: u64 = 1;
 const WRITABLE: u64 = 1 << 1;
 const LARGE: u64 = 1 << 7;
+// Select PAT entry 4, which is write-combining after pat_init
+const PAT_WC: u64 = 1 << 7;
+const LARGE_PAT_WC: u64 = 1 << 12;
+
+// Framebuffers in the linear map are mapped again below the kernel, as the linear map has the
+// memory type from the MTRRs
+const FRAMEBUFFER_ALIAS_BASE: u64 = 0xFFFF_FF80_0000_0000;
+const FRAMEBUFFER_ALIAS_END: u64 = 0xFFFF_FFFF_8000_0000;
+static mut FRAMEBUFFER_ALIAS_NEXT: u64 = FRAMEBUFFER_ALIAS_BASE;
+
+/// Get the table an entry points to, creating it if the entry is empty or splitting it if it maps
+/// a large page of page_size bytes
+unsafe fn paging_next(
+    os: &impl Os,
+    table: &mut [u64],
+    i: usize,
+    page_size: u64,
+) -> Option<&'static mut [u64]> {
+    unsafe {
+        let entry = table[i];
+        if entry & PRESENT != 0 && entry & LARGE == 0 {
+            return Some(slice::from_raw_parts_mut(
+                (entry & ENTRY_ADDRESS_MASK) as *mut u64,
+                PAGE_ENTRIES,
+            ));
+        }
+
+        let next = paging_allocate(os)?;
+        if entry & PRESENT != 0 {
+            let next_size = page_size / PAGE_ENTRIES as u64;
+            let addr = entry & ENTRY_ADDRESS_MASK & !(page_size - 1);
+            let mut flags = (entry & !ENTRY_ADDRESS_MASK) | (entry & LARGE_PAT_WC);
+            if next_size == PAGE_SIZE as u64 {
+                // The PAT bit of 4 KiB pages is where the large page bit is
+                let pat_wc = if flags & LARGE_PAT_WC != 0 { PAT_WC } else { 0 };
+                flags = (flags & !(LARGE | LARGE_PAT_WC)) | pat_wc;
+            }
+            for (next_i, next_entry) in next.iter_mut().enumerate() {
+                *next_entry = (addr + next_i as u64 * next_size) | flags;
+            }
+        }
+        table[i] = next.as_ptr() as u64 | WRITABLE | PRESENT;
+        Some(next)
+    }
+}
+
+/// Map physical memory at virt, using 2 MiB pages where both addresses are aligned and 4 KiB pages
+/// elsewhere, and reusing or splitting existing tables. The range is extended to whole pages.
+unsafe fn paging_map(
+    os: &impl Os,
+    pml4: &mut [u64],
+    virt: u64,
+    phys: u64,
+    size: u64,
+    wc: bool,
+) -> Option<()> {
+    unsafe {
+        let start = phys & !(PAGE_SIZE as u64 - 1);
+        let end = (phys + size).next_multiple_of(PAGE_SIZE as u64);
+        let virt = virt & !(PAGE_SIZE as u64 - 1);
+        let mut addr = start;
+        while addr < end {
+            let page_virt = virt + (addr - start);
+            let pdp = paging_next(
+                os,
+                pml4,
+                (page_virt >> 39) as usize % PAGE_ENTRIES,
+                0x80_0000_0000,
+            )?;
+            let pd = paging_next(
+                os,
+                pdp,
+                (page_virt >> 30) as usize % PAGE_ENTRIES,
+                0x4000_0000,
+            )?;
+            let pd_i = (page_virt >> 21) as usize % PAGE_ENTRIES;
+            if addr.is_multiple_of(0x20_0000)
+                && page_virt.is_multiple_of(0x20_0000)
+                && addr + 0x20_0000 <= end
+            {
+                let pat_wc = if wc { LARGE_PAT_WC } else { 0 };
+                pd[pd_i] = addr | pat_wc | LARGE | WRITABLE | PRESENT;
+                addr += 0x20_0000;
+            } else {
+                let pt = paging_next(os, pd, pd_i, 0x20_0000)?;
+                let pat_wc = if wc { PAT_WC } else { 0 };
+                pt[(page_virt >> 12) as usize % PAGE_ENTRIES] = addr | pat_wc | WRITABLE | PRESENT;
+                addr += PAGE_SIZE as u64;
+            }
+        }
+        Some(())
+    }
+}
 
 pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) -> Option<usize> {
     unsafe {
@@ -35,20 +131,37 @@ pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) ->
         let pml4 = paging_allocate(os)?;
 
         {
-            // Create PDP for identity mapping
-            let pdp = paging_allocate(os)?;
+            // Use 1 GiB pages if supported (PDPE1GB)
+            let huge_pages = cpuid(0x8000_0001).is_some_and(|res| res.edx & 1 << 26 != 0);
+
+            // Identity map all physical memory, also mapping it at PHYS_OFFSET
+            let phys_size = phys_map_size(os, PHYS_MAP_LIMIT);
+            let mut addr = 0;
+            for pml4_i in 0..phys_size.div_ceil(0x80_0000_0000) as usize {
+                // Create PDP for identity mapping
+                let pdp = paging_allocate(os)?;
+
+                // Link user and kernel PML4 entries to PDP
+                pml4[pml4_i] = pdp.as_ptr() as u64 | WRITABLE | PRESENT;
+                pml4[256 + pml4_i] = pdp.as_ptr() as u64 | WRITABLE | PRESENT;
+
+                for pdp_entry in pdp.iter_mut() {
+                    if addr >= phys_size {
+                        break;
+                    }
 
-            // Link first user and first kernel PML4 entry to PDP
-            pml4[0] = pdp.as_ptr() as u64 | WRITABLE | PRESENT;
-            pml4[256] = pdp.as_ptr() as u64 | WRITABLE | PRESENT;
-
-            // Identity map 8 GiB using 2 MiB pages
-            for pdp_i in 0..8 {
-                let pd = paging_allocate(os)?;
-                pdp[pdp_i] = pd.as_ptr() as u64 | WRITABLE | PRESENT;
-                for pd_i in 0..pd.len() {
-                    let addr = pdp_i as u64 * 0x4000_0000 + pd_i as u64 * 0x20_0000;
-                    pd[pd_i] = addr | LARGE | WRITABLE | PRESENT;
+                    if huge_pages {
+                        *pdp_entry = addr | LARGE | WRITABLE | PRESENT;
+                    } else {
+                        // Map 1 GiB using 2 MiB pages
+                        let pd = paging_allocate(os)?;
+                        *pdp_entry = pd.as_ptr() as u64 | WRITABLE | PRESENT;
+                        for pd_i in 0..pd.len() {
+                            pd[pd_i] =
+                                (addr + pd_i as u64 * 0x20_0000) | LARGE | WRITABLE | PRESENT;
+                        }
+                    }
+                    addr += 0x4000_0000;
                 }
             }
         }
@@ -88,6 +201,14 @@ pub unsafe fn paging_create(os: &impl Os, kernel_phys: u64, kernel_size: u64) ->
             assert!(kernel_mapped >= kernel_size);
         }
 
+        if LA57 {
+            // Create PML5, with the same mappings in the first and last 256 TiB
+            let pml5 = paging_allocate(os)?;
+            pml5[0] = pml4.as_ptr() as u64 | WRITABLE | PRESENT;
+            pml5[511] = pml4.as_ptr() as u64 | WRITABLE | PRESENT;
+            return Some(pml5.as_ptr() as usize);
+        }
+
         Some(pml4.as_ptr() as usize)
     }
 }
@@ -99,49 +220,50 @@ pub unsafe fn paging_framebuffer(
     framebuffer_size: u64,
 ) -> Option<u64> {
     unsafe {
-        //TODO: smarter test for framebuffer already mapped
-        if framebuffer_phys + framebuffer_size <= 0x2_0000_0000 {
-            return Some(framebuffer_phys + PHYS_OFFSET);
-        }
-
-        let pml4_i = ((framebuffer_phys / 0x80_0000_0000) + 256) as usize;
-        let mut pdp_i = ((framebuffer_phys % 0x80_0000_0000) / 0x4000_0000) as usize;
-        let mut pd_i = ((framebuffer_phys % 0x4000_0000) / 0x20_0000) as usize;
-        assert_eq!(framebuffer_phys % 0x20_0000, 0);
-
-        let pml4 = slice::from_raw_parts_mut(page_phys as *mut u64, PAGE_ENTRIES);
-
-        // Create PDP for framebuffer mapping
-        let pdp = if pml4[pml4_i] == 0 {
-            let pdp = paging_allocate(os)?;
-            pml4[pml4_i] = pdp.as_ptr() as u64 | 1 << 1 | 1;
-            pdp
+        let pml4 = if LA57 {
+            let pml5 = slice::from_raw_parts_mut(page_phys as *mut u64, PAGE_ENTRIES);
+            slice::from_raw_parts_mut((pml5[511] & ENTRY_ADDRESS_MASK) as *mut u64, PAGE_ENTRIES)
         } else {
-            slice::from_raw_parts_mut(
-                (pml4[pml4_i] & ENTRY_ADDRESS_MASK) as *mut u64,
-                PAGE_ENTRIES,
-            )
+            slice::from_raw_parts_mut(page_phys as *mut u64, PAGE_ENTRIES)
         };
 
-        // Map framebuffer_size at framebuffer offset
-        let mut framebuffer_mapped = 0;
-        while framebuffer_mapped < framebuffer_size && pdp_i < pdp.len() {
-            let pd = paging_allocate(os)?;
-            assert_eq!(pdp[pdp_i], 0);
-            pdp[pdp_i] = pd.as_ptr() as u64 | 1 << 1 | 1;
-
-            while framebuffer_mapped < framebuffer_size && pd_i < pd.len() {
-                let addr = framebuffer_phys + framebuffer_mapped;
-                assert_eq!(pd[pd_i], 0);
-                pd[pd_i] = addr | 1 << 7 | 1 << 1 | 1;
-                framebuffer_mapped += 0x20_0000;
-                pd_i += 1;
+        let pat_wc = pat_supported();
+
+        if framebuffer_phys + framebuffer_size <= phys_map_size(os, PHYS_MAP_LIMIT) {
+            if !pat_wc {
+                return Some(framebuffer_phys + PHYS_OFFSET);
             }
 
-            pdp_i += 1;
-            pd_i = 0;
+            // Keep the alias at the same offset in a 2 MiB page, so that large pages can be used
+            let offset = framebuffer_phys % 0x20_0000;
+            let size = (offset + framebuffer_size).next_multiple_of(0x20_0000);
+            let base = FRAMEBUFFER_ALIAS_NEXT;
+            if base + size > FRAMEBUFFER_ALIAS_END {
+                log::warn!("No space to map framebuffer with write-combining");
+                return Some(framebuffer_phys + PHYS_OFFSET);
+            }
+
+            paging_map(
+                os,
+                pml4,
+                base + offset,
+                framebuffer_phys,
+                framebuffer_size,
+                true,
+            )?;
+            FRAMEBUFFER_ALIAS_NEXT = base + size;
+
+            return Some(base + offset);
         }
-        assert!(framebuffer_mapped >= framebuffer_size);
+
+        paging_map(
+            os,
+            pml4,
+            framebuffer_phys + PHYS_OFFSET,
+            framebuffer_phys,
+            framebuffer_size,
+            pat_wc,
+        )?;
 
         Some(framebuffer_phys + PHYS_OFFSET)
     }
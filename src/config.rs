@@ -0,0 +1,192 @@
+use alloc::{string::String, vec::Vec};
+use core::str;
+
+use crate::os::{Os, OsBootEntry};
+
+/// Path of the configuration file on the boot partition
+const CONFIG_PATH: &str = "redox/bootloader.cfg";
+
+/// Pattern selecting a device to look for RedoxFS on
+// BIOS only looks for RedoxFS on the boot disk
+#[cfg_attr(all(target_arch = "x86", target_os = "none"), allow(dead_code))]
+#[derive(Clone, Debug)]
+pub enum DeviceMatch {
+    /// Part of the text form of a device path, such as `NVMe(0x1)`, ignoring case
+    DevicePath(String),
+    /// RedoxFS UUID
+    Uuid([u8; 16]),
+}
+
+#[cfg_attr(all(target_arch = "x86", target_os = "none"), allow(dead_code))]
+impl DeviceMatch {
+    fn parse(value: &str) -> Self {
+        match parse_uuid(value) {
+            Some(uuid) => DeviceMatch::Uuid(uuid),
+            None => DeviceMatch::DevicePath(String::from(value)),
+        }
+    }
+
+    pub fn matches_device_path(&self, device_path: &str) -> bool {
+        match self {
+            DeviceMatch::DevicePath(pattern) => device_path
+                .to_ascii_lowercase()
+                .contains(&pattern.to_ascii_lowercase()),
+            DeviceMatch::Uuid(_) => false,
+        }
+    }
+
+    pub fn matches_uuid(&self, uuid: &[u8; 16]) -> bool {
+        match self {
+            DeviceMatch::DevicePath(_) => false,
+            DeviceMatch::Uuid(pattern) => pattern == uuid,
+        }
+    }
+}
+
+/// Bootloader configuration, read from `KEY=VALUE` lines where `#` starts a comment
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Load the kernel and initfs from the boot partition instead of RedoxFS
+    pub esp_boot: bool,
+    /// Connect all controllers before looking for RedoxFS, for firmware that only connects the
+    /// boot device
+    pub connect_all: bool,
+    /// Extra boot menu entries, from `CHAINLOAD=path[,name]` lines
+    pub chainload: Vec<OsBootEntry>,
+    /// Seconds before the boot menu starts Redox OS
+    pub boot_menu_timeout: Option<u64>,
+    /// Devices to look for RedoxFS on first, in order, from `DEVICE=pattern` lines
+    pub devices: Vec<DeviceMatch>,
+    /// Use five-level paging on x86_64 if supported, for kernels that support it
+    #[cfg_attr(
+        not(any(target_arch = "x86", target_arch = "x86_64")),
+        allow(dead_code)
+    )]
+    pub la57: bool,
+    /// Largest number of page table levels the kernel supports, used to pick the paging mode on
+    /// riscv64
+    #[cfg_attr(not(target_arch = "riscv64"), allow(dead_code))]
+    pub paging_levels: Option<usize>,
+}
+
+impl Config {
+    pub fn load(os: &impl Os) -> Self {
+        let mut config = Config::default();
+
+        let Some(data) = os.boot_file(CONFIG_PATH) else {
+            return config;
+        };
+        let Ok(text) = str::from_utf8(data) else {
+            log::warn!("{} is not valid UTF-8", CONFIG_PATH);
+            return config;
+        };
+
+        for (line_i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                log::warn!("{}:{}: expected KEY=VALUE", CONFIG_PATH, line_i + 1);
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "ESP_BOOT" => {
+                    if let Some(value) = parse_bool(line_i, value) {
+                        config.esp_boot = value;
+                    }
+                }
+                "CONNECT_ALL" => {
+                    if let Some(value) = parse_bool(line_i, value) {
+                        config.connect_all = value;
+                    }
+                }
+                "LA57" => {
+                    if let Some(value) = parse_bool(line_i, value) {
+                        config.la57 = value;
+                    }
+                }
+                "PAGING_LEVELS" => match value.parse::<usize>() {
+                    Ok(levels @ 3..=5) => config.paging_levels = Some(levels),
+                    _ => log::warn!(
+                        "{}:{}: invalid page table levels {:?}",
+                        CONFIG_PATH,
+                        line_i + 1,
+                        value
+                    ),
+                },
+                "DEVICE" if !value.is_empty() => config.devices.push(DeviceMatch::parse(value)),
+                "CHAINLOAD" => {
+                    let (path, name) = match value.split_once(',') {
+                        Some((path, name)) => (path.trim(), name.trim()),
+                        None => (value, value),
+                    };
+                    if path.is_empty() {
+                        log::warn!("{}:{}: missing chainload path", CONFIG_PATH, line_i + 1);
+                    } else {
+                        config.chainload.push(OsBootEntry {
+                            name: String::from(name),
+                            path: String::from(path),
+                        });
+                    }
+                }
+                "BOOT_MENU_TIMEOUT" => match value.parse::<u64>() {
+                    Ok(seconds) => config.boot_menu_timeout = Some(seconds),
+                    Err(_) => log::warn!(
+                        "{}:{}: invalid timeout {:?}",
+                        CONFIG_PATH,
+                        line_i + 1,
+                        value
+                    ),
+                },
+                _ => log::warn!("{}:{}: unknown key {:?}", CONFIG_PATH, line_i + 1, key),
+            }
+        }
+
+        log::debug!("Config: {:?}", config);
+        config
+    }
+}
+
+/// Parse a UUID in the form printed for RedoxFS, such as `01234567-89ab-cdef-0123-456789abcdef`
+fn parse_uuid(value: &str) -> Option<[u8; 16]> {
+    let digits = value.as_bytes();
+    if digits.len() != 36 {
+        return None;
+    }
+
+    let mut uuid = [0; 16];
+    let mut i = 0;
+    for (pos, &digit) in digits.iter().enumerate() {
+        if matches!(pos, 8 | 13 | 18 | 23) {
+            if digit != b'-' {
+                return None;
+            }
+            continue;
+        }
+
+        let nibble = (digit as char).to_digit(16)? as u8;
+        uuid[i / 2] |= nibble << if i % 2 == 0 { 4 } else { 0 };
+        i += 1;
+    }
+    Some(uuid)
+}
+
+/// Parse a boolean value, warning about invalid values on line line_i
+fn parse_bool(line_i: usize, value: &str) -> Option<bool> {
+    match value {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => {
+            log::warn!(
+                "{}:{}: invalid boolean {:?}",
+                CONFIG_PATH,
+                line_i + 1,
+                value
+            );
+            None
+        }
+    }
+}
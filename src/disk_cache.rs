@@ -0,0 +1,235 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{cmp, mem};
+use redoxfs::{BLOCK_SIZE, Disk};
+use syscall::error::Result;
+
+// Number of blocks kept in the cache (4 MiB)
+const CACHE_BLOCKS: usize = 1024;
+// Number of blocks read past a sequential miss (128 KiB)
+const READ_AHEAD_BLOCKS: u64 = 32;
+// End of the LRU list
+const NONE: usize = usize::MAX;
+
+/// Counts of blocks read through a [DiskCache]
+#[derive(Clone, Copy, Debug)]
+pub struct DiskCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub read_ahead: u64,
+    pub bypassed: u64,
+}
+
+/// Disks that may read through a [DiskCache]
+pub trait CachedDisk {
+    fn cache_stats(&self) -> Option<DiskCacheStats>;
+}
+
+/// Cached block, linked into a list from most to least recently used
+struct CacheEntry {
+    block: u64,
+    prev: usize,
+    next: usize,
+}
+
+/// LRU block cache with sequential read-ahead, wrapping any disk
+///
+/// Reads larger than the read-ahead window, such as kernel and live disk
+/// loading, are passed through to the disk without being cached.
+pub struct DiskCache<D: Disk> {
+    disk: D,
+    size_opt: Option<u64>,
+    map: BTreeMap<u64, usize>,
+    entries: Vec<CacheEntry>,
+    // Most and least recently used entries
+    head: usize,
+    tail: usize,
+    data: Vec<u8>,
+    buffer: Vec<u8>,
+    next_block: u64,
+    hits: u64,
+    misses: u64,
+    read_ahead: u64,
+    bypassed: u64,
+}
+
+impl<D: Disk> DiskCache<D> {
+    pub fn new(mut disk: D) -> Self {
+        let size_opt = disk.size().ok();
+        Self {
+            disk,
+            size_opt,
+            map: BTreeMap::new(),
+            entries: Vec::new(),
+            head: NONE,
+            tail: NONE,
+            data: Vec::new(),
+            buffer: Vec::new(),
+            next_block: 0,
+            hits: 0,
+            misses: 0,
+            read_ahead: 0,
+            bypassed: 0,
+        }
+    }
+
+    pub fn stats(&self) -> DiskCacheStats {
+        DiskCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            read_ahead: self.read_ahead,
+            bypassed: self.bypassed,
+        }
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.entries[i].prev, self.entries[i].next);
+        match prev {
+            NONE => self.head = next,
+            prev => self.entries[prev].next = next,
+        }
+        match next {
+            NONE => self.tail = prev,
+            next => self.entries[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.entries[i].prev = NONE;
+        self.entries[i].next = self.head;
+        match self.head {
+            NONE => self.tail = i,
+            head => self.entries[head].prev = i,
+        }
+        self.head = i;
+    }
+
+    /// Mark an entry as most recently used
+    fn touch(&mut self, i: usize) {
+        if self.head != i {
+            self.unlink(i);
+            self.push_front(i);
+        }
+    }
+
+    fn insert(&mut self, block: u64, data: &[u8]) {
+        let block_size = BLOCK_SIZE as usize;
+
+        let i = if let Some(&i) = self.map.get(&block) {
+            self.touch(i);
+            i
+        } else {
+            let i = if self.entries.len() < CACHE_BLOCKS {
+                self.entries.push(CacheEntry {
+                    block,
+                    prev: NONE,
+                    next: NONE,
+                });
+                self.data.resize(self.entries.len() * block_size, 0);
+                self.entries.len() - 1
+            } else {
+                // Evict least recently used block
+                let i = self.tail;
+                self.unlink(i);
+                self.map.remove(&self.entries[i].block);
+                self.entries[i].block = block;
+                i
+            };
+            self.push_front(i);
+            self.map.insert(block, i);
+            i
+        };
+
+        self.data[i * block_size..(i + 1) * block_size].copy_from_slice(data);
+    }
+}
+
+impl<D: Disk> Disk for DiskCache<D> {
+    unsafe fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let block_size = BLOCK_SIZE as usize;
+        let count = (buffer.len() / block_size) as u64;
+        if !buffer.len().is_multiple_of(block_size) || count > READ_AHEAD_BLOCKS {
+            self.bypassed += 1;
+            return unsafe { self.disk.read_at(block, buffer) };
+        }
+
+        let mut i = 0;
+        while i < count {
+            let chunk_start = i as usize * block_size;
+
+            if let Some(&entry_i) = self.map.get(&(block + i)) {
+                self.touch(entry_i);
+                buffer[chunk_start..chunk_start + block_size]
+                    .copy_from_slice(&self.data[entry_i * block_size..(entry_i + 1) * block_size]);
+                self.hits += 1;
+                i += 1;
+                continue;
+            }
+
+            // Read all following uncached blocks at once
+            let start = block + i;
+            let mut end = start + 1;
+            while end < block + count && !self.map.contains_key(&end) {
+                end += 1;
+            }
+            self.misses += end - start;
+
+            // Read ahead if this miss continues the previous one
+            let mut read_end = end;
+            if start == self.next_block {
+                read_end += READ_AHEAD_BLOCKS;
+                if let Some(size) = self.size_opt {
+                    read_end = cmp::max(cmp::min(read_end, size / BLOCK_SIZE), end);
+                }
+            }
+
+            let mut read_buffer = mem::take(&mut self.buffer);
+            read_buffer.resize(((read_end - start) * BLOCK_SIZE) as usize, 0);
+            let res = match unsafe { self.disk.read_at(start, &mut read_buffer) } {
+                // Read ahead may fail at the end of a disk with unknown size
+                Err(_) if read_end > end => {
+                    read_end = end;
+                    read_buffer.truncate(((read_end - start) * BLOCK_SIZE) as usize);
+                    unsafe { self.disk.read_at(start, &mut read_buffer) }
+                }
+                res => res,
+            };
+            if let Err(err) = res {
+                self.buffer = read_buffer;
+                return Err(err);
+            }
+            self.read_ahead += read_end - end;
+            self.next_block = read_end;
+
+            for (j, chunk) in read_buffer.chunks_exact(block_size).enumerate() {
+                self.insert(start + j as u64, chunk);
+            }
+
+            let len = ((end - start) * BLOCK_SIZE) as usize;
+            buffer[chunk_start..chunk_start + len].copy_from_slice(&read_buffer[..len]);
+            self.buffer = read_buffer;
+
+            i = end - block;
+        }
+
+        Ok(buffer.len())
+    }
+
+    unsafe fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let count = unsafe { self.disk.write_at(block, buffer)? };
+
+        // Keep cached blocks in sync with the disk
+        let block_size = BLOCK_SIZE as usize;
+        for (i, chunk) in buffer.chunks(block_size).enumerate() {
+            if let Some(&entry_i) = self.map.get(&(block + i as u64)) {
+                let entry_start = entry_i * block_size;
+                self.data[entry_start..entry_start + chunk.len()].copy_from_slice(chunk);
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.disk.size()
+    }
+}
@@ -0,0 +1,276 @@
+use alloc::vec::Vec;
+use core::{cmp, fmt};
+
+const BLOCK_SIZE: usize = 128;
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+const EXTENSION_CTA: u8 = 0x02;
+const EXTENSION_DISPLAYID: u8 = 0x70;
+
+/// Vendor and product identification of a display
+#[derive(Clone, Copy, Debug)]
+pub struct EdidIdentity {
+    /// PNP ID of the manufacturer, such as `DEL`
+    pub manufacturer: [u8; 3],
+    pub product: u16,
+    /// Zero if not used
+    pub serial: u32,
+}
+
+impl EdidIdentity {
+    fn parse(base: &[u8]) -> Self {
+        // Three letters of five bits each, with 1 being A
+        let id = u16::from_be_bytes([base[8], base[9]]);
+        let letter = |shift: u16| b'@' + ((id >> shift) & 0x1F) as u8;
+        Self {
+            manufacturer: [letter(10), letter(5), letter(0)],
+            product: u16::from_le_bytes([base[10], base[11]]),
+            serial: u32::from_le_bytes([base[12], base[13], base[14], base[15]]),
+        }
+    }
+}
+
+impl fmt::Display for EdidIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c] = self.manufacturer;
+        write!(
+            f,
+            "{}{}{}:{:04x}:{:08x}",
+            a as char, b as char, c as char, self.product, self.serial
+        )
+    }
+}
+
+/// Resolution listed in an EDID
+#[derive(Clone, Copy, Debug)]
+pub struct EdidMode {
+    pub width: u32,
+    pub height: u32,
+    /// Detailed timings and modes marked native, which the panel shows without scaling
+    pub native: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Edid {
+    pub identity: EdidIdentity,
+    /// Modes without duplicate resolutions, native modes first with the preferred mode at the start
+    pub modes: Vec<EdidMode>,
+    /// Width and height of the image in millimeters, if known
+    pub size_mm: Option<(u32, u32)>,
+}
+
+impl Edid {
+    /// Parse the base block and any extension blocks with a valid checksum
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let base = data.get(..BLOCK_SIZE)?;
+        if base[..8] != HEADER {
+            log::warn!("EDID has invalid header");
+            return None;
+        }
+        if !checksum_valid(base) {
+            log::warn!("EDID has invalid checksum");
+            return None;
+        }
+
+        let mut edid = Self {
+            identity: EdidIdentity::parse(base),
+            modes: Vec::new(),
+            size_mm: None,
+        };
+
+        // Detailed timing descriptors, the first being the preferred mode
+        for descriptor in base[0x36..0x7E].chunks_exact(18) {
+            edid.detailed_timing(descriptor);
+        }
+
+        let extensions = base[0x7E] as usize;
+        for block in data[BLOCK_SIZE..].chunks_exact(BLOCK_SIZE).take(extensions) {
+            if !checksum_valid(block) {
+                log::warn!("EDID extension {:#x} has invalid checksum", block[0]);
+                continue;
+            }
+            match block[0] {
+                EXTENSION_CTA => edid.cta_extension(block),
+                EXTENSION_DISPLAYID => edid.displayid_extension(block),
+                _ => (),
+            }
+        }
+
+        // Standard timings, 1.3 and later use 16:10 instead of 1:1 for aspect ratio 0
+        let revision = base[0x13];
+        for timing in base[0x26..0x36].chunks_exact(2) {
+            if timing == [0x01, 0x01] || timing[0] == 0 {
+                continue;
+            }
+            let width = (timing[0] as u32 + 31) * 8;
+            let height = match timing[1] >> 6 {
+                0 if revision < 3 => width,
+                0 => width * 10 / 16,
+                1 => width * 3 / 4,
+                2 => width * 4 / 5,
+                _ => width * 9 / 16,
+            };
+            edid.add_mode(width, height, false);
+        }
+
+        // Fall back to the screen size in centimeters
+        if edid.size_mm.is_none() && base[0x15] != 0 && base[0x16] != 0 {
+            edid.size_mm = Some((base[0x15] as u32 * 10, base[0x16] as u32 * 10));
+        }
+
+        Some(edid)
+    }
+
+    /// First native mode, which is the preferred mode of the display
+    pub fn preferred(&self) -> Option<&EdidMode> {
+        self.modes.first().filter(|mode| mode.native)
+    }
+
+    pub fn is_native(&self, width: u32, height: u32) -> bool {
+        self.modes
+            .iter()
+            .any(|mode| mode.native && mode.width == width && mode.height == height)
+    }
+
+    /// Horizontal pixels per inch when showing width pixels across the screen
+    pub fn dpi(&self, width: u32) -> Option<u32> {
+        let (width_mm, _height_mm) = self.size_mm?;
+        Some((width as u64 * 254 / (width_mm as u64 * 10)) as u32)
+    }
+
+    fn add_mode(&mut self, width: u32, height: u32, native: bool) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        match self
+            .modes
+            .iter()
+            .position(|mode| mode.width == width && mode.height == height)
+        {
+            Some(i) if native && !self.modes[i].native => {
+                self.modes.remove(i);
+            }
+            Some(_) => return,
+            None => (),
+        }
+
+        let mode = EdidMode {
+            width,
+            height,
+            native,
+        };
+        if native {
+            let i = self.modes.iter().take_while(|mode| mode.native).count();
+            self.modes.insert(i, mode);
+        } else {
+            self.modes.push(mode);
+        }
+    }
+
+    /// Parse an 18 byte detailed timing descriptor, ignoring display descriptors
+    fn detailed_timing(&mut self, descriptor: &[u8]) {
+        if descriptor[0] == 0 && descriptor[1] == 0 {
+            return;
+        }
+
+        let width = descriptor[2] as u32 | ((descriptor[4] as u32 & 0xF0) << 4);
+        let mut height = descriptor[5] as u32 | ((descriptor[7] as u32 & 0xF0) << 4);
+        if descriptor[17] & 0x80 != 0 {
+            // Interlaced, with height being the lines of one field
+            height *= 2;
+        }
+        self.add_mode(width, height, true);
+
+        // Some displays store the aspect ratio instead of the size in millimeters
+        let width_mm = descriptor[12] as u32 | ((descriptor[14] as u32 & 0xF0) << 4);
+        let height_mm = descriptor[13] as u32 | ((descriptor[14] as u32 & 0x0F) << 8);
+        if self.size_mm.is_none() && width_mm > 16 && height_mm > 16 {
+            self.size_mm = Some((width_mm, height_mm));
+        }
+    }
+
+    fn cta_extension(&mut self, block: &[u8]) {
+        // Offset of detailed timings, which ends the data block collection
+        let dtd_offset = block[2] as usize;
+        if !(4..=0x7F).contains(&dtd_offset) {
+            return;
+        }
+
+        let mut i = 4;
+        while i < dtd_offset {
+            let tag = block[i] >> 5;
+            let len = (block[i] & 0x1F) as usize;
+            let Some(payload) = block.get(i + 1..i + 1 + len) else {
+                break;
+            };
+            // Video data block
+            if tag == 2 {
+                for &svd in payload {
+                    // Native modes use 129 to 192 for VICs 1 to 64
+                    let (vic, native) = match svd {
+                        129..=192 => (svd & 0x7F, true),
+                        _ => (svd, false),
+                    };
+                    if let Some((width, height)) = vic_resolution(vic) {
+                        self.add_mode(width, height, native);
+                    }
+                }
+            }
+            i += 1 + len;
+        }
+
+        for descriptor in block[dtd_offset..0x7F].chunks_exact(18) {
+            self.detailed_timing(descriptor);
+        }
+    }
+
+    fn displayid_extension(&mut self, block: &[u8]) {
+        // Section header of version, payload size, product type and extension count, followed by
+        // data blocks and the section checksum
+        let end = cmp::min(5 + block[2] as usize, BLOCK_SIZE - 2);
+        let mut i = 5;
+        while i + 3 <= end {
+            let tag = block[i];
+            let len = block[i + 2] as usize;
+            if i + 3 + len > end {
+                break;
+            }
+            let payload = &block[i + 3..i + 3 + len];
+            // Type I detailed timings of DisplayID 1.3, or type VII of DisplayID 2.0
+            if tag == 0x03 || tag == 0x22 {
+                for timing in payload.chunks_exact(20) {
+                    let width = u16::from_le_bytes([timing[4], timing[5]]) as u32 + 1;
+                    let height = u16::from_le_bytes([timing[12], timing[13]]) as u32 + 1;
+                    self.add_mode(width, height, true);
+                }
+            }
+            i += 3 + len;
+        }
+    }
+}
+
+fn checksum_valid(block: &[u8]) -> bool {
+    block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Resolution of common progressive CTA-861 video identification codes
+fn vic_resolution(vic: u8) -> Option<(u32, u32)> {
+    match vic {
+        1 => Some((640, 480)),
+        2 | 3 => Some((720, 480)),
+        4 | 19 | 47 | 60..=62 => Some((1280, 720)),
+        16 | 31..=34 | 63 | 64 => Some((1920, 1080)),
+        17 | 18 => Some((720, 576)),
+        93..=97 | 103..=107 => Some((3840, 2160)),
+        98..=102 => Some((4096, 2160)),
+        _ => None,
+    }
+}
+
+/// Resolution of the preferred mode
+pub fn preferred_resolution(data: &[u8]) -> Option<(u32, u32)> {
+    let edid = Edid::parse(data)?;
+    let mode = edid.preferred()?;
+    Some((mode.width, mode.height))
+}
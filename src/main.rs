@@ -16,19 +16,30 @@ use core::{
 use redoxfs::{Disk, Node, TreeData};
 
 use self::arch::{paging_create, paging_framebuffer};
-use self::os::{Os, OsHwDesc, OsKey, OsMemoryEntry, OsMemoryKind, OsVideoMode};
+use self::config::Config;
+use self::disk_cache::CachedDisk;
+use self::edid::Edid;
+use self::os::{Os, OsBootEntry, OsHwDesc, OsKey, OsMemoryEntry, OsMemoryKind, OsVideoMode};
+use self::progress::Progress;
 
 #[macro_use]
 mod os;
 
 mod arch;
+mod config;
+mod disk_cache;
+mod edid;
 mod editor;
 mod logger;
+mod progress;
 mod serial_16550;
 
 const KIBI: usize = 1024;
 const MIBI: usize = KIBI * KIBI;
 
+/// Seconds before the boot menu starts Redox OS, without `BOOT_MENU_TIMEOUT` in the config
+const BOOT_MENU_TIMEOUT: u64 = 5;
+
 //TODO: allocate this in a more reasonable manner
 static mut AREAS: [OsMemoryEntry; 1024] = [OsMemoryEntry {
     base: 0,
@@ -114,6 +125,8 @@ fn select_mode(
     live: &mut bool,
     edit_env: &mut bool,
 ) -> Option<OsVideoMode> {
+    let edid_opt = os.video_edid(output_i).and_then(|edid| Edid::parse(&edid));
+
     let mut modes = Vec::new();
     for mode in os.video_modes(output_i) {
         let mut aspect_w = mode.width;
@@ -125,11 +138,19 @@ fn select_mode(
             }
         }
 
+        let native = edid_opt
+            .as_ref()
+            .is_some_and(|edid| edid.is_native(mode.width, mode.height));
+
         modes.push((
             mode,
             format!(
-                "{:>4}x{:<4} {:>3}:{:<3}",
-                mode.width, mode.height, aspect_w, aspect_h
+                "{:>4}x{:<4} {:>3}:{:<3}{}",
+                mode.width,
+                mode.height,
+                aspect_w,
+                aspect_h,
+                if native { "*" } else { "" }
             ),
         ));
     }
@@ -138,15 +159,40 @@ fn select_mode(
         return None;
     }
 
-    // Sort modes by pixel area, reversed
-    modes.sort_by(|a, b| (b.0.width * b.0.height).cmp(&(a.0.width * a.0.height)));
+    // Sort modes by pixel area, reversed, then by bits per pixel, reversed
+    modes.sort_by(|a, b| {
+        (b.0.width * b.0.height)
+            .cmp(&(a.0.width * a.0.height))
+            .then(b.0.bpp.cmp(&a.0.bpp))
+    });
+
+    // Only offer the mode with the most bits per pixel for each resolution
+    modes.dedup_by(|a, b| a.0.width == b.0.width && a.0.height == b.0.height);
+
+    // Only pick a 16 bits per pixel mode by default if there are no 32 bits per pixel modes
+    let default_bpp = modes.iter().map(|(mode, _)| mode.bpp).max().unwrap_or(0);
+    let default_modes = || modes.iter().filter(|(mode, _)| mode.bpp == default_bpp);
 
-    // Set selected based on best resolution
+    // Set selected based on the highest ranked native mode that is available, or best resolution
     print!("Output {}", output_i);
-    let mut selected = modes.first().map_or(0, |x| x.0.id);
-    if let Some((best_width, best_height)) = os.best_resolution(output_i) {
+    if let Some(edid) = &edid_opt {
+        print!(", display {}", edid.identity);
+    }
+    let mut selected = default_modes().next().map_or(0, |x| x.0.id);
+    let native_opt = edid_opt.as_ref().and_then(|edid| {
+        edid.modes.iter().find(|native| {
+            native.native
+                && default_modes()
+                    .any(|(mode, _)| mode.width == native.width && mode.height == native.height)
+        })
+    });
+    let best_opt = match native_opt {
+        Some(native) => Some((native.width, native.height)),
+        None => os.best_resolution(output_i),
+    };
+    if let Some((best_width, best_height)) = best_opt {
         print!(", best resolution: {}x{}", best_width, best_height);
-        for (mode, _text) in modes.iter() {
+        for (mode, _text) in default_modes() {
             if mode.width == best_width && mode.height == best_height {
                 selected = mode.id;
                 break;
@@ -156,11 +202,17 @@ fn select_mode(
     println!();
 
     println!("Arrow keys and enter select mode");
+    if edid_opt.is_some() {
+        println!("Native modes of the display are marked with *");
+    }
     let live_mode = os.get_text_position();
-    if *live {
-        println!("Press l to disable live mode");
-    } else {
-        println!("Press l to  enable live mode");
+    // Live mode cannot be disabled when RedoxFS is only in memory
+    if !os.live_only() {
+        if *live {
+            println!("Press l to disable live mode");
+        } else {
+            println!("Press l to  enable live mode");
+        }
     }
     println!("Press e to edit boot environment");
     println!();
@@ -248,7 +300,7 @@ fn select_mode(
                 }
                 break;
             }
-            OsKey::Char('l') => {
+            OsKey::Char('l') if !os.live_only() => {
                 *live = !*live;
                 os.set_text_position(live_mode.0, live_mode.1);
                 if *live {
@@ -277,7 +329,76 @@ fn select_mode(
     mode_opt
 }
 
-fn redoxfs<O: Os>(os: &O) -> (redoxfs::FileSystem<O::D>, Option<&'static [u8]>) {
+/// Let the user pick an operating system, returning when Redox OS is selected or when no key is
+/// pressed within timeout seconds
+fn boot_menu(os: &impl Os, entries: &[OsBootEntry], timeout: u64) {
+    let mut selected = 0;
+    let mut timeout_opt = Some(timeout);
+    loop {
+        println!("Arrow keys and enter select operating system");
+        println!();
+
+        let (off_x, off_y) = os.get_text_position();
+        loop {
+            for i in 0..=entries.len() {
+                os.set_text_position(off_x, off_y + i);
+                os.set_text_highlight(i == selected);
+                match i.checked_sub(1) {
+                    Some(entry_i) => print!(" {} ", entries[entry_i].name),
+                    None => print!(" Redox OS "),
+                }
+            }
+
+            let key = match timeout_opt {
+                Some(seconds) => {
+                    os.set_text_position(off_x, off_y + entries.len() + 1);
+                    os.set_text_highlight(false);
+                    print!("Starting Redox OS in {} seconds ", seconds);
+                    match os.get_key_timeout(cmp::min(seconds, 1) * 1000) {
+                        Some(key) => {
+                            // Any key stops the countdown
+                            timeout_opt = None;
+                            os.set_text_position(off_x, off_y + entries.len() + 1);
+                            print!("{:1$}", "", 40);
+                            key
+                        }
+                        None if seconds == 0 => break,
+                        None => {
+                            timeout_opt = Some(seconds - 1);
+                            continue;
+                        }
+                    }
+                }
+                None => os.get_key(),
+            };
+
+            match key {
+                OsKey::Up => selected = selected.checked_sub(1).unwrap_or(entries.len()),
+                OsKey::Down => selected = (selected + 1) % (entries.len() + 1),
+                OsKey::Enter => break,
+                _ => (),
+            }
+        }
+
+        os.set_text_position(0, off_y + entries.len() + 1);
+        os.set_text_highlight(false);
+        println!();
+
+        let Some(entry_i) = selected.checked_sub(1) else {
+            return;
+        };
+        os.chainload(&entries[entry_i]);
+
+        println!("Press any key to return to the boot menu");
+        os.get_key();
+        os.clear_text();
+    }
+}
+
+/// RedoxFS and the password used to unlock it, copied to page aligned memory
+type RedoxFsPassword<O> = (redoxfs::FileSystem<<O as Os>::D>, Option<&'static [u8]>);
+
+fn redoxfs<O: Os>(os: &O, config: &Config) -> Option<RedoxFsPassword<O>> {
     let attempts = 10;
     for attempt in 0..=attempts {
         let mut password_opt = None;
@@ -312,9 +433,9 @@ fn redoxfs<O: Os>(os: &O) -> (redoxfs::FileSystem<O::D>, Option<&'static [u8]>)
                 password_opt = Some(password);
             }
         }
-        match os.filesystem(password_opt.as_ref().map(|x| x.as_bytes())) {
+        match os.filesystem(&config.devices, password_opt.as_ref().map(|x| x.as_bytes())) {
             Ok(fs) => {
-                return (
+                return Some((
                     fs,
                     password_opt.map(|password| {
                         // Copy password to page aligned memory
@@ -332,11 +453,13 @@ fn redoxfs<O: Os>(os: &O) -> (redoxfs::FileSystem<O::D>, Option<&'static [u8]>)
                             slice::from_raw_parts(password_base, password_size)
                         }
                     }),
-                );
+                ));
             }
             Err(err) => match err.errno {
                 // Incorrect password, try again
                 syscall::ENOKEY => (),
+                // Kernel and initfs may still be loaded from the boot partition
+                syscall::ENOENT => return None,
                 _ => {
                     panic!("Failed to open RedoxFS: {}", err);
                 }
@@ -351,6 +474,21 @@ enum Filetype {
     Elf,
     Initfs,
 }
+
+fn check_magic(path: &str, slice: &[u8], filetype: Filetype) {
+    if filetype == Filetype::Elf {
+        let magic = &slice[..4];
+        if magic != b"\x7FELF" {
+            panic!("{} has invalid magic number {:#X?}", path, magic);
+        }
+    } else if filetype == Filetype::Initfs {
+        let magic = &slice[..8];
+        if magic != b"RedoxFtw" {
+            panic!("{} has invalid magic number {:#X?}", path, magic);
+        }
+    }
+}
+
 fn load_to_memory<O: Os>(
     os: &O,
     fs: &mut redoxfs::FileSystem<O::D>,
@@ -391,17 +529,7 @@ fn load_to_memory<O: Os>(
         }
         println!("\r{}: {}/{} MiB", path, i / MIBI as u64, size / MIBI as u64);
 
-        if filetype == Filetype::Elf {
-            let magic = &slice[..4];
-            if magic != b"\x7FELF" {
-                panic!("{} has invalid magic number {:#X?}", path, magic);
-            }
-        } else if filetype == Filetype::Initfs {
-            let magic = &slice[..8];
-            if magic != b"RedoxFtw" {
-                panic!("{} has invalid magic number {:#X?}", path, magic);
-            }
-        }
+        check_magic(path, slice, filetype);
 
         Ok(slice)
     })
@@ -413,6 +541,77 @@ fn load_to_memory<O: Os>(
     })
 }
 
+fn load_boot_file<O: Os>(os: &O, path: &str, filetype: Filetype) -> &'static mut [u8] {
+    let slice = os
+        .boot_file(path)
+        .unwrap_or_else(|| panic!("Failed to find {} on boot partition", path));
+
+    check_magic(path, slice, filetype);
+
+    slice
+}
+
+fn load_live<O: Os>(os: &O, fs: &mut redoxfs::FileSystem<O::D>, size: u64) -> &'static [u8] {
+    let ptr = os.alloc_zeroed_page_aligned(size as usize);
+    if ptr.is_null() {
+        panic!("Failed to allocate memory for live");
+    }
+
+    let live = unsafe { slice::from_raw_parts_mut(ptr, size as usize) };
+
+    // Only allocated blocks are copied, free blocks are left zeroed
+    let mut free_ranges = Vec::new();
+    for (level, indexes) in fs.allocator().levels().iter().enumerate() {
+        for &index in indexes.iter() {
+            free_ranges.push((index, index + (1 << level)));
+        }
+    }
+    free_ranges.sort_unstable();
+
+    let progress = Progress::new(os, "live", size);
+    let blocks = size / redoxfs::BLOCK_SIZE;
+    let chunk_blocks = MIBI as u64 / redoxfs::BLOCK_SIZE;
+    let mut free_iter = free_ranges.iter().peekable();
+    let mut block = 0;
+    let mut read = 0;
+    while block < blocks {
+        // Skip free blocks
+        while let Some(&&(free_start, free_end)) = free_iter.peek() {
+            if free_start > block {
+                break;
+            }
+            block = cmp::max(block, free_end);
+            free_iter.next();
+        }
+        if block >= blocks {
+            break;
+        }
+
+        // Read allocated blocks up to the next free range
+        let mut end = cmp::min(block + chunk_blocks, blocks);
+        if let Some(&&(free_start, _)) = free_iter.peek() {
+            end = cmp::min(end, free_start);
+        }
+        let chunk =
+            &mut live[(block * redoxfs::BLOCK_SIZE) as usize..(end * redoxfs::BLOCK_SIZE) as usize];
+        read += unsafe {
+            fs.disk
+                .read_at(fs.block + block, chunk)
+                .expect("Failed to read live disk") as u64
+        };
+        block = end;
+
+        progress.update(block * redoxfs::BLOCK_SIZE, read);
+    }
+    progress.finish(size, read);
+
+    let live: &'static [u8] = live;
+    unsafe {
+        LIVE_OPT = Some((fs.block, live));
+    }
+    live
+}
+
 fn elf_entry(data: &[u8]) -> (u64, bool) {
     match (data[4], data[5]) {
         // 32-bit, little endian
@@ -464,21 +663,60 @@ fn main(os: &impl Os) -> (usize, u64, KernelArgs) {
         OsHwDesc::NotFound => (0, 0),
     };
 
-    let (mut fs, password_opt) = redoxfs(os);
+    let config = Config::load(os);
 
-    print!("RedoxFS ");
-    for i in 0..fs.header.uuid().len() {
-        if i == 4 || i == 6 || i == 8 || i == 10 {
-            print!("-");
+    let mut boot_entries = config.chainload.clone();
+    for entry in os.boot_entries() {
+        if !boot_entries
+            .iter()
+            .any(|other| other.path.eq_ignore_ascii_case(&entry.path))
+        {
+            boot_entries.push(entry);
         }
+    }
+    if !boot_entries.is_empty() {
+        boot_menu(
+            os,
+            &boot_entries,
+            config.boot_menu_timeout.unwrap_or(BOOT_MENU_TIMEOUT),
+        );
+    }
 
-        print!("{:>02x}", fs.header.uuid()[i]);
+    os.connect_devices(config.connect_all);
+
+    let (mut fs_opt, password_opt) = match redoxfs(os, &config) {
+        Some((fs, password_opt)) => (Some(fs), password_opt),
+        None => {
+            println!("No RedoxFS found");
+            (None, None)
+        }
+    };
+
+    if let Some(fs) = &mut fs_opt {
+        print!("RedoxFS ");
+        for i in 0..fs.header.uuid().len() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                print!("-");
+            }
+
+            print!("{:>02x}", fs.header.uuid()[i]);
+        }
+        println!(": {} MiB", fs.header.size() / MIBI as u64);
+        if let Ok(disk_size) = fs.disk.size() {
+            let fs_end = fs.block * redoxfs::BLOCK_SIZE + fs.header.size();
+            if fs_end > disk_size {
+                log::warn!(
+                    "RedoxFS ends at {} MiB, past the end of the disk at {} MiB",
+                    fs_end / MIBI as u64,
+                    disk_size / MIBI as u64
+                );
+            }
+        }
     }
-    println!(": {} MiB", fs.header.size() / MIBI as u64);
     println!();
 
     let mut mode_opts = Vec::new();
-    let mut live = cfg!(feature = "live");
+    let mut live = (cfg!(feature = "live") || os.live_only()) && fs_opt.is_some();
     let mut edit_env = false;
     for output_i in 0..os.video_outputs() {
         if output_i > 0 {
@@ -493,33 +731,17 @@ fn main(os: &impl Os) -> (usize, u64, KernelArgs) {
         panic!("Failed to allocate memory for stack");
     }
 
-    let live_opt = if live {
+    let live_opt = if let (true, Some(fs)) = (live, &mut fs_opt) {
         let size = fs.header.size();
 
-        print!("live: 0/{} MiB", size / MIBI as u64);
-
-        let ptr = os.alloc_zeroed_page_aligned(size as usize);
-        if ptr.is_null() {
-            panic!("Failed to allocate memory for live");
-        }
-
-        let live = unsafe { slice::from_raw_parts_mut(ptr, size as usize) };
-
-        let mut i = 0;
-        for chunk in live.chunks_mut(MIBI) {
-            print!("\rlive: {}/{} MiB", i / MIBI as u64, size / MIBI as u64);
-            i += unsafe {
-                fs.disk
-                    .read_at(fs.block + i / redoxfs::BLOCK_SIZE, chunk)
-                    .expect("Failed to read live disk") as u64
-            };
-        }
-        println!("\rlive: {}/{} MiB", i / MIBI as u64, size / MIBI as u64);
-
+        let live = match unsafe { LIVE_OPT } {
+            // The filesystem was already loaded into memory, so it is used without a copy
+            Some((block, live)) if block == fs.block && live.len() as u64 >= size => {
+                &live[..size as usize]
+            }
+            _ => load_live(os, fs, size),
+        };
         println!("Switching to live disk");
-        unsafe {
-            LIVE_OPT = Some((fs.block, slice::from_raw_parts_mut(ptr, size as usize)));
-        }
 
         area_add(OsMemoryEntry {
             base: live.as_ptr() as u64,
@@ -533,7 +755,12 @@ fn main(os: &impl Os) -> (usize, u64, KernelArgs) {
     };
 
     let (kernel, kernel_entry) = {
-        let kernel = load_to_memory(os, &mut fs, "usr/lib/boot/kernel", Filetype::Elf);
+        let kernel = match &mut fs_opt {
+            Some(fs) if !config.esp_boot => {
+                load_to_memory(os, fs, "usr/lib/boot/kernel", Filetype::Elf)
+            }
+            _ => load_boot_file(os, "redox/kernel", Filetype::Elf),
+        };
         let (kernel_entry, kernel_64bit) = elf_entry(kernel);
         unsafe {
             KERNEL_64BIT = kernel_64bit;
@@ -542,19 +769,62 @@ fn main(os: &impl Os) -> (usize, u64, KernelArgs) {
     };
 
     let (bootstrap_size, bootstrap_base) = {
-        let initfs_slice = load_to_memory(os, &mut fs, "usr/lib/boot/initfs", Filetype::Initfs);
-
-        let memory = unsafe {
-            let total_size = initfs_slice.len().next_multiple_of(4096);
-            let ptr = os.alloc_zeroed_page_aligned(total_size);
-            assert!(!ptr.is_null(), "failed to allocate bootstrap+initfs memory");
-            core::slice::from_raw_parts_mut(ptr, total_size)
+        // The initfs is loaded into zeroed, page aligned memory, so it is used as the bootstrap
+        // memory directly, including the padding up to the next page
+        let initfs_slice = match &mut fs_opt {
+            Some(fs) if !config.esp_boot => {
+                load_to_memory(os, fs, "usr/lib/boot/initfs", Filetype::Initfs)
+            }
+            _ => load_boot_file(os, "redox/initfs", Filetype::Initfs),
         };
-        memory[..initfs_slice.len()].copy_from_slice(initfs_slice);
 
-        (memory.len() as u64, memory.as_mut_ptr() as u64)
+        (
+            initfs_slice.len().next_multiple_of(os.page_size()) as u64,
+            initfs_slice.as_mut_ptr() as u64,
+        )
     };
 
+    // The filesystem is not dropped before starting the kernel, so log the cache use here
+    if let Some(stats) = fs_opt.as_ref().and_then(|fs| fs.disk.cache_stats()) {
+        log::debug!(
+            "DiskCache: {} hits, {} misses, {} blocks read ahead, {} reads bypassed",
+            stats.hits,
+            stats.misses,
+            stats.read_ahead,
+            stats.bypassed
+        );
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if config.la57 && unsafe { KERNEL_64BIT } {
+        // The BIOS bootloader is always loaded below 4 GiB
+        #[cfg(target_arch = "x86")]
+        let below_4g = true;
+        #[cfg(target_arch = "x86_64")]
+        let below_4g = os::la57_entry_below_4g();
+
+        if !arch::la57_supported() {
+            log::warn!("Five-level paging not supported, using four-level paging");
+        } else if !below_4g {
+            log::warn!(
+                "Five-level paging needs the bootloader below 4 GiB, using four-level paging"
+            );
+        } else {
+            unsafe {
+                arch::LA57 = true;
+            }
+        }
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    arch::paging_select(
+        match hwdesc {
+            OsHwDesc::DeviceTree(addr, _) => os::dtb::dtb_mmu_levels(addr),
+            _ => None,
+        },
+        config.paging_levels,
+    );
+
     let page_phys = unsafe { paging_create(os, kernel.as_ptr() as u64, kernel.len() as u64) }
         .expect("Failed to set up paging");
 
@@ -583,32 +853,37 @@ fn main(os: &impl Os) -> (usize, u64, KernelArgs) {
             OsHwDesc::NotFound => {}
         }
 
-        if let Some(live) = live_opt {
-            writeln!(w, "DISK_LIVE_ADDR={:016x}", live.as_ptr() as usize).unwrap();
-            writeln!(w, "DISK_LIVE_SIZE={:016x}", live.len()).unwrap();
-            writeln!(w, "REDOXFS_BLOCK={:016x}", 0).unwrap();
-        } else {
-            writeln!(w, "REDOXFS_BLOCK={:016x}", fs.block).unwrap();
-        }
-        write!(w, "REDOXFS_UUID=").unwrap();
-        for i in 0..fs.header.uuid().len() {
-            if i == 4 || i == 6 || i == 8 || i == 10 {
-                write!(w, "-").unwrap();
+        if let Some(fs) = &fs_opt {
+            if let Some(live) = live_opt {
+                writeln!(w, "DISK_LIVE_ADDR={:016x}", live.as_ptr() as usize).unwrap();
+                writeln!(w, "DISK_LIVE_SIZE={:016x}", live.len()).unwrap();
+                writeln!(w, "REDOXFS_BLOCK={:016x}", 0).unwrap();
+            } else {
+                writeln!(w, "REDOXFS_BLOCK={:016x}", fs.block).unwrap();
             }
+            write!(w, "REDOXFS_UUID=").unwrap();
+            for i in 0..fs.header.uuid().len() {
+                if i == 4 || i == 6 || i == 8 || i == 10 {
+                    write!(w, "-").unwrap();
+                }
 
-            write!(w, "{:>02x}", fs.header.uuid()[i]).unwrap();
-        }
-        writeln!(w).unwrap();
-        if let Some(password) = password_opt {
-            writeln!(
-                w,
-                "REDOXFS_PASSWORD_ADDR={:016x}",
-                password.as_ptr() as usize
-            )
-            .unwrap();
-            writeln!(w, "REDOXFS_PASSWORD_SIZE={:016x}", password.len()).unwrap();
+                write!(w, "{:>02x}", fs.header.uuid()[i]).unwrap();
+            }
+            writeln!(w).unwrap();
+            if let Some(password) = password_opt {
+                writeln!(
+                    w,
+                    "REDOXFS_PASSWORD_ADDR={:016x}",
+                    password.as_ptr() as usize
+                )
+                .unwrap();
+                writeln!(w, "REDOXFS_PASSWORD_SIZE={:016x}", password.len()).unwrap();
+            }
         }
 
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "riscv64"))]
+        writeln!(w, "PAGING_LEVELS={:016x}", arch::paging_levels()).unwrap();
+
         #[cfg(target_arch = "riscv64")]
         {
             let boot_hartid = os::efi_get_boot_hartid()
@@ -624,22 +899,24 @@ fn main(os: &impl Os) -> (usize, u64, KernelArgs) {
                 // Set mode to get updated values
                 os.set_video_mode(output_i, &mut mode);
 
-                if output_i == 0 {
-                    let virt = unsafe {
-                        paging_framebuffer(
-                            os,
-                            page_phys,
-                            mode.base,
-                            (mode.stride * mode.height * 4) as u64,
-                        )
-                    }
-                    .expect("Failed to map framebuffer");
+                let Some(virt) = (unsafe {
+                    paging_framebuffer(
+                        os,
+                        page_phys,
+                        mode.base,
+                        (mode.stride * mode.height * mode.bytes_per_pixel()) as u64,
+                    )
+                }) else {
+                    log::warn!("Failed to map framebuffer of output {}", output_i);
+                    continue;
+                };
 
+                let prefix = if output_i == 0 {
                     writeln!(w, "FRAMEBUFFER_ADDR={:016x}", mode.base).unwrap();
-                    writeln!(w, "FRAMEBUFFER_VIRT={virt:016x}").unwrap();
                     writeln!(w, "FRAMEBUFFER_WIDTH={:016x}", mode.width).unwrap();
                     writeln!(w, "FRAMEBUFFER_HEIGHT={:016x}", mode.height).unwrap();
                     writeln!(w, "FRAMEBUFFER_STRIDE={:016x}", mode.stride).unwrap();
+                    String::from("FRAMEBUFFER")
                 } else {
                     writeln!(
                         w,
@@ -647,6 +924,43 @@ fn main(os: &impl Os) -> (usize, u64, KernelArgs) {
                         output_i, mode.base, mode.width, mode.height, mode.stride,
                     )
                     .unwrap();
+                    format!("FRAMEBUFFER{output_i}")
+                };
+
+                writeln!(w, "{prefix}_VIRT={virt:016x}").unwrap();
+                writeln!(w, "{prefix}_FORMAT={}", mode.format()).unwrap();
+                writeln!(w, "{prefix}_BPP={:016x}", mode.bpp).unwrap();
+                writeln!(w, "{prefix}_RED_MASK={:016x}", mode.red_mask).unwrap();
+                writeln!(w, "{prefix}_GREEN_MASK={:016x}", mode.green_mask).unwrap();
+                writeln!(w, "{prefix}_BLUE_MASK={:016x}", mode.blue_mask).unwrap();
+
+                if let Some(data) = os.video_edid(output_i) {
+                    if let Some(edid) = Edid::parse(&data) {
+                        writeln!(w, "{prefix}_EDID_ID={}", edid.identity).unwrap();
+                        if let Some(dpi) = edid.dpi(mode.width) {
+                            let key = if output_i == 0 {
+                                String::from("DISPLAY_DPI")
+                            } else {
+                                format!("DISPLAY{output_i}_DPI")
+                            };
+                            writeln!(w, "{key}={dpi:016x}").unwrap();
+                        }
+                    }
+
+                    // Copy raw EDID, including extension blocks, to page aligned memory
+                    let edid_base = os.alloc_zeroed_page_aligned(data.len());
+                    if !edid_base.is_null() {
+                        area_add(OsMemoryEntry {
+                            base: edid_base as u64,
+                            size: data.len() as u64,
+                            kind: OsMemoryKind::Reserved,
+                        });
+                        unsafe {
+                            ptr::copy(data.as_ptr(), edid_base, data.len());
+                        }
+                        writeln!(w, "{prefix}_EDID_ADDR={:016x}", edid_base as usize).unwrap();
+                        writeln!(w, "{prefix}_EDID_SIZE={:016x}", data.len()).unwrap();
+                    }
                 }
             }
         }
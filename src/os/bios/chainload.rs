@@ -0,0 +1,182 @@
+use alloc::{format, vec::Vec};
+use core::ptr;
+use redoxfs::{BLOCK_SIZE, Disk};
+
+use crate::os::OsBootEntry;
+
+use super::disk::DiskBios;
+use super::{CHAINLOAD_ADDR, CHAINLOAD_MBR_ADDR, OsBios, ThunkData};
+
+const BOOT_SECTOR_SIZE: usize = 512;
+const PARTITION_TABLE_OFFSET: usize = 0x1BE;
+const PARTITION_ENTRY_SIZE: usize = 16;
+// Number of hard disks, from the BIOS data area
+const BDA_HARD_DISKS_ADDR: usize = 0x475;
+
+const PARTITION_TYPE_EMPTY: u8 = 0x00;
+const PARTITION_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const PARTITION_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// Read the boot sector at the start of a disk sector, which may not be aligned to a RedoxFS
+/// block. Partition LBAs are in units of the sector size of the disk.
+fn read_sector(disk: &mut DiskBios, lba: u64) -> Option<[u8; BOOT_SECTOR_SIZE]> {
+    let sector_size = disk.sector_size();
+    let sectors_per_block = BLOCK_SIZE / sector_size;
+    let mut block = [0; BLOCK_SIZE as usize];
+    if let Err(err) = unsafe { disk.read_at(lba / sectors_per_block, &mut block) } {
+        log::warn!("Failed to read sector {}: {}", lba, err);
+        return None;
+    }
+
+    let offset = ((lba % sectors_per_block) * sector_size) as usize;
+    let mut sector = [0; BOOT_SECTOR_SIZE];
+    sector.copy_from_slice(&block[offset..offset + BOOT_SECTOR_SIZE]);
+    Some(sector)
+}
+
+fn is_boot_sector(sector: &[u8; BOOT_SECTOR_SIZE]) -> bool {
+    sector[510..] == [0x55, 0xAA]
+}
+
+/// Primary partitions with type and starting LBA
+fn mbr_partitions(mbr: &[u8; BOOT_SECTOR_SIZE]) -> impl Iterator<Item = (usize, u8, u64)> + '_ {
+    (0..4).filter_map(|i| {
+        let entry =
+            &mbr[PARTITION_TABLE_OFFSET + i * PARTITION_ENTRY_SIZE..][..PARTITION_ENTRY_SIZE];
+        let kind = entry[4];
+        let lba = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64;
+        if kind == PARTITION_TYPE_EMPTY
+            || kind == PARTITION_TYPE_GPT_PROTECTIVE
+            || PARTITION_TYPE_EXTENDED.contains(&kind)
+            || lba == 0
+        {
+            None
+        } else {
+            Some((i, kind, lba))
+        }
+    })
+}
+
+/// Parse a boot entry path, either `<drive>` for the MBR of a drive or `<drive>,<partition>` for
+/// the VBR of a primary partition, with the drive number in hexadecimal
+fn parse_path(path: &str) -> Option<(u8, Option<usize>)> {
+    let (drive, partition) = match path.split_once(',') {
+        Some((drive, partition)) => (drive, Some(partition)),
+        None => (path, None),
+    };
+    let drive = u8::from_str_radix(drive.trim_start_matches("0x"), 16).ok()?;
+    let partition = match partition {
+        Some(partition) => Some(partition.parse::<usize>().ok().filter(|&i| i < 4)?),
+        None => None,
+    };
+    Some((drive, partition))
+}
+
+/// Find boot sectors of other drives and of other partitions on the boot drive
+pub fn bios_boot_entries(os: &OsBios) -> Vec<OsBootEntry> {
+    let mut entries = Vec::new();
+
+    let hard_disks = unsafe { ptr::read(BDA_HARD_DISKS_ADDR as *const u8) };
+    for drive in 0x80..0x80u8.saturating_add(hard_disks) {
+        let Ok(mut disk) = DiskBios::new(drive, os.thunk13) else {
+            continue;
+        };
+        let Some(mbr) = read_sector(&mut disk, 0) else {
+            continue;
+        };
+        if !is_boot_sector(&mbr) {
+            continue;
+        }
+
+        if drive as usize != os.boot_disk {
+            // The MBR of another drive picks its own partition to boot
+            entries.push(OsBootEntry {
+                name: format!("Disk 0x{:02X}", drive),
+                path: format!("0x{:02X}", drive),
+            });
+            continue;
+        }
+
+        // This program is in the MBR of the boot drive, so start partitions directly
+        for (i, kind, lba) in mbr_partitions(&mbr) {
+            if read_sector(&mut disk, lba).is_some_and(|vbr| is_boot_sector(&vbr)) {
+                entries.push(OsBootEntry {
+                    name: format!(
+                        "Disk 0x{:02X} partition {} (type 0x{:02X})",
+                        drive,
+                        i + 1,
+                        kind
+                    ),
+                    path: format!("0x{:02X},{}", drive, i),
+                });
+            }
+        }
+    }
+
+    entries
+}
+
+/// Load a boot sector at 0x7C00 and jump to it in real mode, only returning on failure
+pub fn bios_chainload(os: &OsBios, path: &str) {
+    let Some((drive, partition_opt)) = parse_path(path) else {
+        log::error!("Invalid BIOS boot entry {:?}", path);
+        return;
+    };
+
+    let Ok(mut disk) = DiskBios::new(drive, os.thunk13) else {
+        return;
+    };
+    let Some(mbr) = read_sector(&mut disk, 0) else {
+        return;
+    };
+
+    let (sector, partition_entry) = match partition_opt {
+        Some(partition) => {
+            let Some((_, _, lba)) = mbr_partitions(&mbr).find(|&(i, _, _)| i == partition) else {
+                log::error!(
+                    "Partition {} not found on disk 0x{:02X}",
+                    partition + 1,
+                    drive
+                );
+                return;
+            };
+            let Some(vbr) = read_sector(&mut disk, lba) else {
+                return;
+            };
+            (
+                vbr,
+                CHAINLOAD_MBR_ADDR + PARTITION_TABLE_OFFSET + partition * PARTITION_ENTRY_SIZE,
+            )
+        }
+        None => (mbr, 0),
+    };
+    if !is_boot_sector(&sector) {
+        log::error!("No boot signature in {}", path);
+        return;
+    }
+
+    unsafe {
+        // Like an MBR that relocated itself, DS:SI points to the partition entry in a copy of
+        // the MBR at 0x600
+        ptr::copy(
+            mbr.as_ptr(),
+            CHAINLOAD_MBR_ADDR as *mut u8,
+            BOOT_SECTOR_SIZE,
+        );
+        // This overwrites stage 1, which is no longer needed
+        ptr::copy(sector.as_ptr(), CHAINLOAD_ADDR as *mut u8, BOOT_SECTOR_SIZE);
+
+        // Restore the cursor that was disabled at startup
+        let mut data = ThunkData::new();
+        data.eax = 0x0100;
+        data.ecx = 0x0607;
+        data.with(os.thunk10);
+
+        let mut data = ThunkData::new();
+        data.edx = drive as u32;
+        data.esi = partition_entry as u32;
+        data.with(os.thunk_chainload);
+    }
+
+    unreachable!("chainloaded boot sector returned");
+}
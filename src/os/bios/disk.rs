@@ -1,15 +1,19 @@
-use core::{mem, ptr};
+use core::{cmp, mem, ptr};
 use redoxfs::{BLOCK_SIZE, Disk};
 use syscall::error::{EIO, Error, Result};
 
-use super::{DISK_ADDRESS_PACKET_ADDR, DISK_BIOS_ADDR, ThunkData};
+use crate::disk_cache::{CachedDisk, DiskCache, DiskCacheStats};
 
+use super::{DISK_ADDRESS_PACKET_ADDR, DISK_BIOS_ADDR, DISK_PARAMETERS_ADDR, ThunkData};
+
+// Sector size of hard disks, and of all disks when extensions are not installed
 const SECTOR_SIZE: u64 = 512;
-const BLOCKS_PER_SECTOR: u64 = BLOCK_SIZE / SECTOR_SIZE;
-// 128 sectors is the amount allocated for DISK_BIOS_ADDR
+// Optical drives without emulation use 2048 byte sectors
+const MAX_SECTOR_SIZE: u64 = BLOCK_SIZE;
+// 64 KiB is the amount allocated for DISK_BIOS_ADDR
+const DISK_BIOS_SIZE: u64 = 64 * 1024;
 // 127 sectors is the maximum for many BIOSes
 const MAX_SECTORS: u64 = 127;
-const MAX_BLOCKS: u64 = MAX_SECTORS * SECTOR_SIZE / BLOCK_SIZE;
 
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
@@ -24,10 +28,11 @@ pub struct DiskAddressPacket {
 }
 
 impl DiskAddressPacket {
-    pub fn from_block(block: u64, count: u64) -> DiskAddressPacket {
-        let address = block * BLOCKS_PER_SECTOR;
-        let sectors = count * BLOCKS_PER_SECTOR;
-        assert!(sectors <= MAX_SECTORS);
+    pub fn from_block(block: u64, count: u64, sector_size: u64) -> DiskAddressPacket {
+        let sectors_per_block = BLOCK_SIZE / sector_size;
+        let address = block * sectors_per_block;
+        let sectors = count * sectors_per_block;
+        assert!(sectors <= max_sectors(sector_size));
         DiskAddressPacket {
             size: mem::size_of::<DiskAddressPacket>() as u8,
             reserved: 0,
@@ -39,15 +44,102 @@ impl DiskAddressPacket {
     }
 }
 
+/// Maximum sectors per read, limited by the BIOS and by the size of DISK_BIOS_ADDR
+fn max_sectors(sector_size: u64) -> u64 {
+    cmp::min(MAX_SECTORS, DISK_BIOS_SIZE / sector_size)
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct DiskParameters {
+    size: u16,
+    flags: u16,
+    cylinders: u32,
+    heads: u32,
+    sectors_per_track: u32,
+    sectors: u64,
+    bytes_per_sector: u16,
+    edd_config: u32,
+}
+
+impl DiskParameters {
+    pub fn new() -> DiskParameters {
+        DiskParameters {
+            size: mem::size_of::<DiskParameters>() as u16,
+            flags: 0,
+            cylinders: 0,
+            heads: 0,
+            sectors_per_track: 0,
+            sectors: 0,
+            bytes_per_sector: 0,
+            edd_config: 0,
+        }
+    }
+}
+
+pub enum DiskOrMemoryBios {
+    Disk(DiskCache<DiskBios>),
+    /// Image already in memory, starting at the given block
+    Memory(u64, &'static [u8]),
+}
+
+impl Disk for DiskOrMemoryBios {
+    unsafe fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        match self {
+            DiskOrMemoryBios::Disk(disk) => unsafe { disk.read_at(block, buffer) },
+            DiskOrMemoryBios::Memory(start_block, data) => {
+                let start = block
+                    .checked_sub(*start_block)
+                    .map(|offset| (offset * BLOCK_SIZE) as usize);
+                match start.and_then(|start| data.get(start..start + buffer.len())) {
+                    Some(slice) => {
+                        buffer.copy_from_slice(slice);
+                        Ok(buffer.len())
+                    }
+                    None => Err(Error::new(EIO)),
+                }
+            }
+        }
+    }
+
+    unsafe fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        match self {
+            DiskOrMemoryBios::Disk(disk) => unsafe { disk.write_at(block, buffer) },
+            DiskOrMemoryBios::Memory(_, _) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        match self {
+            DiskOrMemoryBios::Disk(disk) => disk.size(),
+            DiskOrMemoryBios::Memory(start_block, data) => {
+                Ok(*start_block * BLOCK_SIZE + data.len() as u64)
+            }
+        }
+    }
+}
+
+impl CachedDisk for DiskOrMemoryBios {
+    fn cache_stats(&self) -> Option<DiskCacheStats> {
+        match self {
+            DiskOrMemoryBios::Disk(disk) => Some(disk.stats()),
+            DiskOrMemoryBios::Memory(_, _) => None,
+        }
+    }
+}
+
 pub struct DiskBios {
     boot_disk: u8,
     thunk13: extern "C" fn(),
     chs_opt: Option<(u32, u32, u32)>,
+    sector_size: u64,
+    size_opt: Option<u64>,
 }
 
 impl DiskBios {
-    pub fn new(boot_disk: u8, thunk13: extern "C" fn()) -> Self {
-        let chs_opt = unsafe {
+    pub fn new(boot_disk: u8, thunk13: extern "C" fn()) -> Result<Self> {
+        let (chs_opt, sector_size, size_opt) = unsafe {
             let mut data = ThunkData::new();
             data.eax = 0x4100;
             data.ebx = 0x55AA;
@@ -57,7 +149,54 @@ impl DiskBios {
 
             if (data.ebx & 0xFFFF) == 0xAA55 {
                 // Extensions are installed, do not use CHS
-                None
+                ptr::write(
+                    DISK_PARAMETERS_ADDR as *mut DiskParameters,
+                    DiskParameters::new(),
+                );
+
+                data = ThunkData::new();
+                data.eax = 0x4800;
+                data.edx = boot_disk as u32;
+                data.esi = DISK_PARAMETERS_ADDR as u32;
+
+                data.with(thunk13);
+
+                let ah = ({ data.eax } >> 8) & 0xFF;
+                let params = ptr::read(DISK_PARAMETERS_ADDR as *const DiskParameters);
+                let sectors = params.sectors;
+                let bytes_per_sector = params.bytes_per_sector as u64;
+                if ah != 0 {
+                    log::warn!(
+                        "Failed to get drive parameters for disk 0x{:02X}: 0x{:02X}",
+                        boot_disk,
+                        ah
+                    );
+                }
+
+                let sector_size = if ah == 0
+                    && bytes_per_sector.is_power_of_two()
+                    && (SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&bytes_per_sector)
+                {
+                    bytes_per_sector
+                } else {
+                    if ah == 0 {
+                        log::warn!(
+                            "Unsupported sector size {} for disk 0x{:02X}, using {}",
+                            bytes_per_sector,
+                            boot_disk,
+                            SECTOR_SIZE
+                        );
+                    }
+                    SECTOR_SIZE
+                };
+
+                let size_opt = if ah == 0 && sectors != 0 {
+                    Some(sectors * sector_size)
+                } else {
+                    None
+                };
+
+                (None, sector_size, size_opt)
             } else {
                 // Extensions are not installed, get CHS geometry
                 data = ThunkData::new();
@@ -67,23 +206,46 @@ impl DiskBios {
 
                 data.with(thunk13);
 
-                //TODO: return result on error
                 let ah = ({ data.eax } >> 8) & 0xFF;
-                assert_eq!(ah, 0);
+                if ah != 0 {
+                    log::error!(
+                        "Failed to get drive geometry for disk 0x{:02X}: 0x{:02X}",
+                        boot_disk,
+                        ah
+                    );
+                    return Err(Error::new(EIO));
+                }
 
                 let c = (data.ecx >> 8) & 0xFF | ((data.ecx >> 6) & 0x3) << 8;
                 let h = ((data.edx >> 8) & 0xFF) + 1;
                 let s = data.ecx & 0x3F;
+                if s == 0 {
+                    log::error!("Invalid drive geometry for disk 0x{:02X}", boot_disk);
+                    return Err(Error::new(EIO));
+                }
 
-                Some((c, h, s))
+                // Cylinder is the maximum cylinder number, not the count
+                let size = (c as u64 + 1) * h as u64 * s as u64 * SECTOR_SIZE;
+
+                (Some((c, h, s)), SECTOR_SIZE, Some(size))
             }
         };
 
-        Self {
+        if sector_size != SECTOR_SIZE {
+            log::debug!("Disk 0x{:02X} has {} byte sectors", boot_disk, sector_size);
+        }
+
+        Ok(Self {
             boot_disk,
             thunk13,
             chs_opt,
-        }
+            sector_size,
+            size_opt,
+        })
+    }
+
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
     }
 }
 
@@ -102,25 +264,48 @@ impl Disk for DiskBios {
                 }
             }
 
+            if let Some(size) = self.size_opt {
+                let end = block
+                    .checked_mul(BLOCK_SIZE)
+                    .and_then(|start| start.checked_add(buffer.len() as u64));
+                if end.is_none_or(|end| end > size) {
+                    log::error!(
+                        "DiskBios::read_at(0x{:X}, 0x{:X}) past end of disk 0x{:X}",
+                        block,
+                        buffer.len(),
+                        size
+                    );
+                    return Err(Error::new(EIO));
+                }
+            }
+
+            let max_blocks = max_sectors(self.sector_size) * self.sector_size / BLOCK_SIZE;
             for (i, chunk) in buffer
-                .chunks_mut((MAX_BLOCKS * BLOCK_SIZE) as usize)
+                .chunks_mut((max_blocks * BLOCK_SIZE) as usize)
                 .enumerate()
             {
                 let dap = DiskAddressPacket::from_block(
-                    block + i as u64 * MAX_BLOCKS,
+                    block + i as u64 * max_blocks,
                     chunk.len() as u64 / BLOCK_SIZE,
+                    self.sector_size,
                 );
 
                 if let Some((_, h_max, s_max)) = self.chs_opt {
                     let s = (dap.address % s_max as u64) + 1;
-                    assert!(s <= 63, "invalid sector {s}");
-
                     let tmp = dap.address / s_max as u64;
                     let h = tmp % h_max as u64;
-                    assert!(h <= 255, "invalid head {h}");
-
                     let c = tmp / h_max as u64;
-                    assert!(c <= 1023, "invalid cylinder {c}");
+                    if s > 63 || h > 255 || c > 1023 {
+                        log::error!(
+                            "DiskBios::read_at(0x{:X}) invalid CHS {}/{}/{} for disk 0x{:02X}",
+                            block,
+                            c,
+                            h,
+                            s,
+                            self.boot_disk
+                        );
+                        return Err(Error::new(EIO));
+                    }
 
                     let mut data = ThunkData::new();
                     data.eax = 0x0200 | (dap.sectors as u32);
@@ -132,9 +317,16 @@ impl Disk for DiskBios {
 
                     data.with(self.thunk13);
 
-                    //TODO: return result on error
                     let ah = ({ data.eax } >> 8) & 0xFF;
-                    assert_eq!(ah, 0);
+                    if ah != 0 {
+                        log::error!(
+                            "Failed to read disk 0x{:02X} at 0x{:X}: 0x{:02X}",
+                            self.boot_disk,
+                            { dap.address },
+                            ah
+                        );
+                        return Err(Error::new(EIO));
+                    }
                 } else {
                     ptr::write(DISK_ADDRESS_PACKET_ADDR as *mut DiskAddressPacket, dap);
 
@@ -145,9 +337,16 @@ impl Disk for DiskBios {
 
                     data.with(self.thunk13);
 
-                    //TODO: return result on error
                     let ah = ({ data.eax } >> 8) & 0xFF;
-                    assert_eq!(ah, 0);
+                    if ah != 0 {
+                        log::error!(
+                            "Failed to read disk 0x{:02X} at 0x{:X}: 0x{:02X}",
+                            self.boot_disk,
+                            { dap.address },
+                            ah
+                        );
+                        return Err(Error::new(EIO));
+                    }
 
                     //TODO: check blocks transferred
                     // dap = ptr::read(DISK_ADDRESS_PACKET_ADDR as *mut DiskAddressPacket);
@@ -171,7 +370,12 @@ impl Disk for DiskBios {
     }
 
     fn size(&mut self) -> Result<u64> {
-        log::error!("DiskBios::size not implemented");
-        Err(Error::new(EIO))
+        match self.size_opt {
+            Some(size) => Ok(size),
+            None => {
+                log::error!("DiskBios::size unknown for disk 0x{:02X}", self.boot_disk);
+                Err(Error::new(EIO))
+            }
+        }
     }
 }
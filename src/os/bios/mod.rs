@@ -1,14 +1,22 @@
-use alloc::alloc::{Layout, alloc_zeroed};
+use alloc::{
+    alloc::{Layout, alloc_zeroed},
+    vec::Vec,
+};
 use core::{convert::TryFrom, mem, ptr, slice};
 use linked_list_allocator::LockedHeap;
 use spin::Mutex;
 
 use crate::KernelArgs;
+use crate::config::DeviceMatch;
+use crate::disk_cache::DiskCache;
+use crate::edid;
 use crate::logger::LOGGER;
-use crate::os::{Os, OsHwDesc, OsKey, OsVideoMode};
+use crate::os::{Os, OsBootEntry, OsHwDesc, OsKey, OsVideoMode};
 
-use self::disk::DiskBios;
-use self::memory_map::memory_map;
+use self::chainload::{bios_boot_entries, bios_chainload};
+use self::disk::{DiskBios, DiskOrMemoryBios};
+use self::memory_map::{MemoryMapIter, memory_map};
+use self::pxe::{pxe_live_image, pxe_shutdown};
 use self::thunk::ThunkData;
 use self::vbe::VideoModeIter;
 use self::vga::{Vga, VgaTextColor};
@@ -16,9 +24,11 @@ use self::vga::{Vga, VgaTextColor};
 #[macro_use]
 mod macros;
 
+mod chainload;
 mod disk;
 mod memory_map;
 mod panic;
+mod pxe;
 pub(crate) mod serial;
 mod thunk;
 mod vbe;
@@ -26,13 +36,18 @@ mod vga;
 
 // Real mode memory allocation, for use with thunk
 // 0x500 to 0x7BFF is free
+const CHAINLOAD_MBR_ADDR: usize = 0x600; // 512 bytes, ends at 0x7FF
 const DISK_BIOS_ADDR: usize = 0x70000; // 64 KiB at 448 KiB, ends at 512 KiB
 const VBE_CARD_INFO_ADDR: usize = 0x1000; // 512 bytes, ends at 0x11FF
 const VBE_MODE_INFO_ADDR: usize = 0x1200; // 256 bytes, ends at 0x12FF
 const VBE_EDID_ADDR: usize = 0x1300; // 128 bytes, ends at 0x137F
 const MEMORY_MAP_ADDR: usize = 0x1380; // 24 bytes, ends at 0x1397
 const DISK_ADDRESS_PACKET_ADDR: usize = 0x1398; // 16 bytes, ends at 0x13A7
+const DISK_PARAMETERS_ADDR: usize = 0x13A8; // 30 bytes, ends at 0x13C5
+const PXE_PARAMS_ADDR: usize = 0x13C8; // 256 bytes, ends at 0x14C7
+const PXE_BUFFER_ADDR: usize = DISK_BIOS_ADDR; // Not used at the same time as disk reads
 const THUNK_STACK_ADDR: usize = 0x7C00; // Grows downwards
+const CHAINLOAD_ADDR: usize = 0x7C00; // 512 bytes, replaces stage 1
 const VGA_ADDR: usize = 0xB8000;
 
 #[global_allocator]
@@ -46,6 +61,8 @@ pub struct OsBios {
     thunk13: extern "C" fn(),
     thunk15: extern "C" fn(),
     thunk16: extern "C" fn(),
+    thunk_chainload: extern "C" fn(),
+    thunk_pxe: Option<extern "C" fn()>,
 }
 
 #[allow(dead_code)]
@@ -97,7 +114,7 @@ unsafe fn search_rsdp(start: usize, end: usize) -> Option<(u64, u64)> {
 }
 
 impl Os for OsBios {
-    type D = DiskBios;
+    type D = DiskOrMemoryBios;
     type V = VideoModeIter;
 
     fn name(&self) -> &str {
@@ -121,17 +138,60 @@ impl Os for OsBios {
         4096
     }
 
+    fn memory_end(&self) -> u64 {
+        MemoryMapIter::new(self.thunk15)
+            .map(|entry| entry.base + entry.size)
+            .max()
+            .unwrap_or(0)
+    }
+
     fn filesystem(
         &self,
+        // Only the boot disk is searched
+        _devices: &[DeviceMatch],
         password_opt: Option<&[u8]>,
-    ) -> syscall::Result<redoxfs::FileSystem<DiskBios>> {
-        let disk = DiskBios::new(u8::try_from(self.boot_disk).unwrap(), self.thunk13);
-
+    ) -> syscall::Result<redoxfs::FileSystem<DiskOrMemoryBios>> {
         //TODO: get block from partition table
         let block = 2 * crate::MIBI as u64 / redoxfs::BLOCK_SIZE;
+
+        let disk = if let Some(thunk_pxe) = self.thunk_pxe {
+            // Booted from the network, so the filesystem comes from the live image on the boot
+            // server. It is kept in LIVE_OPT when the password has to be asked for again.
+            let live = match unsafe { crate::LIVE_OPT } {
+                Some((_, live)) => live,
+                None => {
+                    let image = pxe_live_image(self, thunk_pxe)
+                        .ok_or(syscall::Error::new(syscall::ENOENT))?;
+                    let live = image
+                        .get((block * redoxfs::BLOCK_SIZE) as usize..)
+                        .ok_or(syscall::Error::new(syscall::ENOENT))?;
+                    // The image is already in memory, so live mode can use it without copying
+                    unsafe {
+                        crate::LIVE_OPT = Some((block, live));
+                    }
+                    live
+                }
+            };
+            DiskOrMemoryBios::Memory(block, live)
+        } else {
+            DiskOrMemoryBios::Disk(DiskCache::new(DiskBios::new(
+                u8::try_from(self.boot_disk).unwrap(),
+                self.thunk13,
+            )?))
+        };
+
         redoxfs::FileSystem::open(disk, password_opt, Some(block), false)
     }
 
+    fn live_only(&self) -> bool {
+        self.thunk_pxe.is_some()
+    }
+
+    fn boot_file(&self, _path: &str) -> Option<&'static mut [u8]> {
+        // The boot partition is not a filesystem on BIOS
+        None
+    }
+
     fn hwdesc(&self) -> OsHwDesc {
         // See ACPI specification - Finding the RSDP on IA-PC Systems
         unsafe {
@@ -149,6 +209,26 @@ impl Os for OsBios {
         OsHwDesc::NotFound
     }
 
+    fn connect_devices(&self, _connect_all: bool) {
+        // All drives are available through INT 13h
+    }
+
+    fn boot_entries(&self) -> Vec<OsBootEntry> {
+        bios_boot_entries(self)
+    }
+
+    fn chainload(&self, entry: &OsBootEntry) {
+        bios_chainload(self, &entry.path);
+    }
+
+    fn time_ms(&self) -> Option<u64> {
+        // BIOS timer ticks since midnight, at 1193182 / 65536 Hz. The timer interrupt is only
+        // handled while a thunk runs with interrupts enabled, which is where disk reads spend
+        // most of their time.
+        let ticks = unsafe { ptr::read_volatile(0x46C as *const u32) };
+        Some(ticks as u64 * 65536 * 1000 / 1193182)
+    }
+
     fn video_outputs(&self) -> usize {
         //TODO: return 1 only if vbe supported?
         1
@@ -169,28 +249,42 @@ impl Os for OsBios {
         //TODO: check result
     }
 
-    fn best_resolution(&self, _output_i: usize) -> Option<(u32, u32)> {
-        let mut data = ThunkData::new();
-        data.eax = 0x4F15;
-        data.ebx = 0x01;
-        data.ecx = 0;
-        data.edx = 0;
-        data.edi = VBE_EDID_ADDR as u32;
-        unsafe {
-            data.with(self.thunk10);
-        }
+    fn best_resolution(&self, output_i: usize) -> Option<(u32, u32)> {
+        edid::preferred_resolution(&self.video_edid(output_i)?)
+    }
 
-        if data.eax == 0x4F {
-            let edid = unsafe { slice::from_raw_parts(VBE_EDID_ADDR as *const u8, 128) };
+    fn video_edid(&self, _output_i: usize) -> Option<Vec<u8>> {
+        let mut edid = Vec::new();
+        let mut blocks = 1;
+        while edid.len() < blocks * 128 {
+            let mut data = ThunkData::new();
+            data.eax = 0x4F15;
+            data.ebx = 0x01;
+            data.ecx = 0;
+            data.edx = (edid.len() / 128) as u32;
+            data.edi = VBE_EDID_ADDR as u32;
+            unsafe {
+                data.with(self.thunk10);
+            }
 
-            Some((
-                (edid[0x38] as u32) | (((edid[0x3A] as u32) & 0xF0) << 4),
-                (edid[0x3B] as u32) | (((edid[0x3D] as u32) & 0xF0) << 4),
-            ))
-        } else {
-            log::warn!("Failed to get VBE EDID: 0x{:X}", { data.eax });
-            None
+            if data.eax != 0x4F {
+                log::warn!(
+                    "Failed to get VBE EDID block {}: 0x{:X}",
+                    edid.len() / 128,
+                    { data.eax }
+                );
+                break;
+            }
+
+            let block = unsafe { slice::from_raw_parts(VBE_EDID_ADDR as *const u8, 128) };
+            if edid.is_empty() {
+                // Number of extension blocks
+                blocks += block[0x7E] as usize;
+            }
+            edid.extend_from_slice(block);
         }
+
+        if edid.is_empty() { None } else { Some(edid) }
     }
 
     fn get_key(&self) -> OsKey {
@@ -214,6 +308,36 @@ impl Os for OsBios {
         }
     }
 
+    fn get_key_timeout(&self, timeout_ms: u64) -> Option<OsKey> {
+        let start = self.time_ms().unwrap_or(0);
+        loop {
+            // Check for a keypress, which also lets the BIOS handle timer and keyboard interrupts
+            let mut data = ThunkData::new();
+            data.eax = 0x0100;
+            unsafe {
+                data.with(self.thunk16);
+            }
+
+            // The thunk does not return the zero flag, so compare the head and tail of the
+            // keyboard buffer in the BIOS data area instead
+            let (head, tail) = unsafe {
+                (
+                    ptr::read_volatile(0x41A as *const u16),
+                    ptr::read_volatile(0x41C as *const u16),
+                )
+            };
+            if head != tail {
+                return Some(self.get_key());
+            }
+
+            // The timer wraps around at midnight
+            let now = self.time_ms().unwrap_or(0);
+            if now < start || now - start >= timeout_ms {
+                return None;
+            }
+        }
+    }
+
     fn clear_text(&self) {
         let mut vga = VGA.lock();
         vga.clear();
@@ -257,6 +381,8 @@ pub unsafe extern "C" fn start(
     thunk13: extern "C" fn(),
     thunk15: extern "C" fn(),
     thunk16: extern "C" fn(),
+    thunk_chainload: extern "C" fn(),
+    thunk_pxe: Option<extern "C" fn()>,
 ) -> ! {
     unsafe {
         #[cfg(feature = "serial_debug")]
@@ -293,6 +419,8 @@ pub unsafe extern "C" fn start(
             thunk13,
             thunk15,
             thunk16,
+            thunk_chainload,
+            thunk_pxe,
         };
 
         let (heap_start, heap_size) = memory_map(os.thunk15).expect("No memory for heap");
@@ -301,6 +429,13 @@ pub unsafe extern "C" fn start(
 
         let (page_phys, func, args) = crate::main(&mut os);
 
+        // The live image is in memory, so the network is no longer needed
+        if let Some(thunk_pxe) = thunk_pxe {
+            pxe_shutdown(thunk_pxe);
+        }
+
+        crate::arch::pat_init();
+
         kernel_entry(
             page_phys,
             args.stack_base
@@ -312,7 +447,13 @@ pub unsafe extern "C" fn start(
                 },
             func,
             &args,
-            if crate::KERNEL_64BIT { 1 } else { 0 },
+            if !crate::KERNEL_64BIT {
+                0
+            } else if crate::arch::LA57 {
+                2
+            } else {
+                1
+            },
         );
     }
 }
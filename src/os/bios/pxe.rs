@@ -0,0 +1,329 @@
+use core::{cmp, mem, ptr, slice};
+
+use crate::os::Os;
+use crate::progress::Progress;
+
+use super::{OsBios, PXE_BUFFER_ADDR, PXE_PARAMS_ADDR, ThunkData};
+
+const PXENV_EXIT_SUCCESS: u32 = 0x0000;
+
+const PXENV_UNDI_SHUTDOWN: u16 = 0x0005;
+const PXENV_STOP_UNDI: u16 = 0x0015;
+const PXENV_TFTP_OPEN: u16 = 0x0020;
+const PXENV_TFTP_CLOSE: u16 = 0x0021;
+const PXENV_TFTP_READ: u16 = 0x0022;
+const PXENV_TFTP_GET_FSIZE: u16 = 0x0025;
+const PXENV_UNLOAD_STACK: u16 = 0x0070;
+const PXENV_GET_CACHED_INFO: u16 = 0x0071;
+
+const PXENV_PACKET_TYPE_DHCP_ACK: u16 = 2;
+const PXENV_PACKET_TYPE_CACHED_REPLY: u16 = 3;
+
+const TFTP_PORT: u16 = 69;
+// Largest TFTP packet that fits in an ethernet frame
+const TFTP_PACKET_SIZE: u16 = 1456;
+
+// Offsets into a BOOTP/DHCPv4 packet
+const BOOTP_SIADDR: usize = 20;
+const BOOTP_GIADDR: usize = 24;
+const BOOTP_FILE: usize = 108;
+const BOOTP_FILE_LEN: usize = 128;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct PxenvGetCachedInfo {
+    status: u16,
+    packet_type: u16,
+    buffer_size: u16,
+    buffer_offset: u16,
+    buffer_segment: u16,
+    buffer_limit: u16,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct PxenvTftpGetFsize {
+    status: u16,
+    server_ip: [u8; 4],
+    gateway_ip: [u8; 4],
+    filename: [u8; 128],
+    file_size: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct PxenvTftpOpen {
+    status: u16,
+    server_ip: [u8; 4],
+    gateway_ip: [u8; 4],
+    filename: [u8; 128],
+    port: u16,
+    packet_size: u16,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct PxenvTftpRead {
+    status: u16,
+    packet_number: u16,
+    buffer_size: u16,
+    buffer_offset: u16,
+    buffer_segment: u16,
+}
+
+/// Parameters of calls that only return a status, such as TFTP_CLOSE
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct PxenvStatus {
+    status: u16,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct PxenvUnloadStack {
+    status: u16,
+    reserved: [u8; 10],
+}
+
+/// Call the PXE API, returning the PXE status on failure
+unsafe fn pxe_call<T: Copy>(thunk_pxe: extern "C" fn(), opcode: u16, params: T) -> Result<T, u16> {
+    assert!(mem::size_of::<T>() <= 256);
+    unsafe {
+        ptr::write(PXE_PARAMS_ADDR as *mut T, params);
+
+        let mut data = ThunkData::new();
+        data.ebx = opcode as u32;
+        data.edi = PXE_PARAMS_ADDR as u32;
+
+        data.with(thunk_pxe);
+
+        let params = ptr::read(PXE_PARAMS_ADDR as *const T);
+        if (data.eax & 0xFFFF) == PXENV_EXIT_SUCCESS {
+            Ok(params)
+        } else {
+            // All parameter structures start with the status
+            Err(ptr::read(PXE_PARAMS_ADDR as *const u16))
+        }
+    }
+}
+
+/// Get the BOOTP packet with the boot server and boot file, preferring the PXE server reply
+unsafe fn cached_info(thunk_pxe: extern "C" fn()) -> Option<&'static [u8]> {
+    for packet_type in [PXENV_PACKET_TYPE_CACHED_REPLY, PXENV_PACKET_TYPE_DHCP_ACK] {
+        let params = PxenvGetCachedInfo {
+            status: 0,
+            packet_type,
+            // Use the internal buffer of the PXE stack
+            buffer_size: 0,
+            buffer_offset: 0,
+            buffer_segment: 0,
+            buffer_limit: 0,
+        };
+        let info = match unsafe { pxe_call(thunk_pxe, PXENV_GET_CACHED_INFO, params) } {
+            Ok(info) => info,
+            Err(status) => {
+                log::warn!(
+                    "Failed to get PXE cached info {}: 0x{:X}",
+                    packet_type,
+                    status
+                );
+                continue;
+            }
+        };
+
+        let addr = ((info.buffer_segment as usize) << 4) + info.buffer_offset as usize;
+        let size = info.buffer_size as usize;
+        if addr == 0 || size < BOOTP_FILE + BOOTP_FILE_LEN {
+            continue;
+        }
+
+        let packet = unsafe { slice::from_raw_parts(addr as *const u8, size) };
+        // Cached replies without a server address do not come from a boot server
+        if packet[BOOTP_SIADDR..BOOTP_SIADDR + 4] != [0; 4] {
+            return Some(packet);
+        }
+    }
+    None
+}
+
+/// Stop the network interface and release the memory of the PXE stack, which must not be used
+/// afterwards
+pub fn pxe_shutdown(thunk_pxe: extern "C" fn()) {
+    // The TFTP connection was closed after downloading the live image
+    if let Err(status) =
+        unsafe { pxe_call(thunk_pxe, PXENV_UNDI_SHUTDOWN, PxenvStatus { status: 0 }) }
+    {
+        log::warn!("Failed to shut down PXE UNDI: 0x{:X}", status);
+    }
+
+    let params = PxenvUnloadStack {
+        status: 0,
+        reserved: [0; 10],
+    };
+    if let Err(status) = unsafe { pxe_call(thunk_pxe, PXENV_UNLOAD_STACK, params) } {
+        log::warn!("Failed to unload PXE stack: 0x{:X}", status);
+        return;
+    }
+
+    if let Err(status) = unsafe { pxe_call(thunk_pxe, PXENV_STOP_UNDI, PxenvStatus { status: 0 }) }
+    {
+        log::warn!("Failed to stop PXE UNDI: 0x{:X}", status);
+    }
+}
+
+/// Download redox-live.iso over TFTP from the server this program was netbooted from
+///
+/// The image is looked up in the same directory as the boot file.
+pub fn pxe_live_image(os: &OsBios, thunk_pxe: extern "C" fn()) -> Option<&'static [u8]> {
+    let packet = match unsafe { cached_info(thunk_pxe) } {
+        Some(packet) => packet,
+        None => {
+            log::warn!("No PXE boot server found, not loading redox-live.iso");
+            return None;
+        }
+    };
+
+    let mut server_ip = [0; 4];
+    server_ip.copy_from_slice(&packet[BOOTP_SIADDR..BOOTP_SIADDR + 4]);
+    let mut gateway_ip = [0; 4];
+    gateway_ip.copy_from_slice(&packet[BOOTP_GIADDR..BOOTP_GIADDR + 4]);
+
+    // Replace the boot file name with redox-live.iso, keeping its directory
+    let boot_file = &packet[BOOTP_FILE..BOOTP_FILE + BOOTP_FILE_LEN];
+    let boot_file_len = boot_file
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(boot_file.len());
+    let dir_len = boot_file[..boot_file_len]
+        .iter()
+        .rposition(|&b| b == b'/' || b == b'\\')
+        .map_or(0, |i| i + 1);
+    let live_name = b"redox-live.iso";
+    if dir_len + live_name.len() >= BOOTP_FILE_LEN {
+        log::warn!("PXE boot file path too long, not loading redox-live.iso");
+        return None;
+    }
+    let mut filename = [0; BOOTP_FILE_LEN];
+    filename[..dir_len].copy_from_slice(&boot_file[..dir_len]);
+    filename[dir_len..dir_len + live_name.len()].copy_from_slice(live_name);
+    let name =
+        core::str::from_utf8(&filename[..dir_len + live_name.len()]).unwrap_or("redox-live.iso");
+
+    let [a, b, c, d] = server_ip;
+    let params = PxenvTftpGetFsize {
+        status: 0,
+        server_ip,
+        gateway_ip,
+        filename,
+        file_size: 0,
+    };
+    let size = match unsafe { pxe_call(thunk_pxe, PXENV_TFTP_GET_FSIZE, params) } {
+        Ok(params) => params.file_size as u64,
+        Err(status) => {
+            log::warn!(
+                "Failed to get size of {} on TFTP server {}.{}.{}.{}: 0x{:X}",
+                name,
+                a,
+                b,
+                c,
+                d,
+                status
+            );
+            return None;
+        }
+    };
+    if size == 0 {
+        log::warn!("{} on TFTP server {}.{}.{}.{} is empty", name, a, b, c, d);
+        return None;
+    }
+
+    let ptr = os.alloc_zeroed_page_aligned(size as usize);
+    let buffer = unsafe { slice::from_raw_parts_mut(ptr, size as usize) };
+
+    let params = PxenvTftpOpen {
+        status: 0,
+        server_ip,
+        gateway_ip,
+        filename,
+        port: TFTP_PORT.to_be(),
+        packet_size: TFTP_PACKET_SIZE,
+    };
+    if let Err(status) = unsafe { pxe_call(thunk_pxe, PXENV_TFTP_OPEN, params) } {
+        log::warn!(
+            "Failed to open {} on TFTP server {}.{}.{}.{}: 0x{:X}",
+            name,
+            a,
+            b,
+            c,
+            d,
+            status
+        );
+        return None;
+    }
+
+    // Packets are read one at a time into real mode memory
+    let progress = Progress::new(os, name, size);
+    let mut i = 0;
+    let res = loop {
+        let params = PxenvTftpRead {
+            status: 0,
+            packet_number: 0,
+            buffer_size: 0,
+            buffer_offset: (PXE_BUFFER_ADDR & 0xF) as u16,
+            buffer_segment: (PXE_BUFFER_ADDR >> 4) as u16,
+        };
+        let count = match unsafe { pxe_call(thunk_pxe, PXENV_TFTP_READ, params) } {
+            Ok(params) => params.buffer_size as usize,
+            Err(status) => break Err(status),
+        };
+
+        let end = cmp::min(i + count, buffer.len());
+        unsafe {
+            ptr::copy(
+                PXE_BUFFER_ADDR as *const u8,
+                buffer[i..end].as_mut_ptr(),
+                end - i,
+            );
+        }
+        i = end;
+        progress.update(i as u64, i as u64);
+
+        // Stop at the reported size, or if the server ends the transfer early
+        if count == 0 || i == buffer.len() {
+            break Ok(());
+        }
+    };
+
+    let _ = unsafe { pxe_call(thunk_pxe, PXENV_TFTP_CLOSE, PxenvStatus { status: 0 }) };
+
+    match res {
+        Ok(()) if i == buffer.len() => {
+            progress.finish(size, size);
+            Some(buffer)
+        }
+        Ok(()) => {
+            println!();
+            log::warn!("{} ended at {} of {} bytes", name, i, size);
+            None
+        }
+        Err(status) => {
+            println!();
+            log::warn!(
+                "Failed to read {} from TFTP server {}.{}.{}.{}: 0x{:X}",
+                name,
+                a,
+                b,
+                c,
+                d,
+                status
+            );
+            None
+        }
+    }
+}
@@ -124,15 +124,41 @@ impl Iterator for VideoModeIter {
             if data.eax == 0x004F {
                 let mode_info = unsafe { ptr::read(VBE_MODE_INFO_ADDR as *const VbeModeInfo) };
 
-                // We only support 32-bits per pixel modes
-                if mode_info.bitsperpixel != 32 {
+                // We only support 16 and 32 bits per pixel modes, 24 bits per pixel modes rarely
+                // have a stride that is a whole number of pixels
+                let bpp = mode_info.bitsperpixel as u32;
+                if bpp != 16 && bpp != 32 {
                     continue;
                 }
 
                 let width = mode_info.xresolution as u32;
                 let height = mode_info.yresolution as u32;
-                //TODO: support stride that is not a multiple of 4
-                let stride = mode_info.bytesperscanline as u32 / 4;
+                let bytes_per_pixel = bpp / 8;
+                if !(mode_info.bytesperscanline as u32).is_multiple_of(bytes_per_pixel) {
+                    continue;
+                }
+                let stride = mode_info.bytesperscanline as u32 / bytes_per_pixel;
+
+                // Shifting by 32 overflows, so a 32 bit mask is handled separately
+                let mask = |size: u8, position: u8| {
+                    1u32.checked_shl(size as u32)
+                        .map_or(u32::MAX, |bit| bit - 1)
+                        .checked_shl(position as u32)
+                        .unwrap_or(0)
+                };
+                let mut masks = [
+                    mask(mode_info.redmasksize, mode_info.redfieldposition),
+                    mask(mode_info.greenmasksize, mode_info.greenfieldposition),
+                    mask(mode_info.bluemasksize, mode_info.bluefieldposition),
+                ];
+                if masks == [0; 3] {
+                    // VBE before 1.2 does not report masks
+                    masks = if bpp == 32 {
+                        OsVideoMode::BGRX_MASKS
+                    } else {
+                        [0xF800, 0x07E0, 0x001F]
+                    };
+                }
 
                 return Some(OsVideoMode {
                     id: mode as u32,
@@ -140,6 +166,10 @@ impl Iterator for VideoModeIter {
                     height,
                     stride,
                     base: mode_info.physbaseptr as u64,
+                    bpp,
+                    red_mask: masks[0],
+                    green_mask: masks[1],
+                    blue_mask: masks[2],
                 });
             } else {
                 error!("Failed to read VBE mode 0x{:04X} info: 0x{:04X}", mode, {
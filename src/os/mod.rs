@@ -1,5 +1,10 @@
+use alloc::{string::String, vec::Vec};
+use core::{cmp, fmt::Write};
 use redoxfs::Disk;
 
+use crate::config::DeviceMatch;
+use crate::disk_cache::CachedDisk;
+
 #[cfg(all(target_arch = "x86", target_os = "none"))]
 pub use self::bios::*;
 
@@ -54,39 +59,115 @@ pub struct OsMemoryEntry {
     pub kind: OsMemoryKind,
 }
 
+/// Another operating system or boot program that can be started from the boot menu
+#[derive(Clone, Debug)]
+pub struct OsBootEntry {
+    pub name: String,
+    /// Location in a format specific to the Os implementation
+    pub path: String,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct OsVideoMode {
     pub id: u32,
     pub width: u32,
     pub height: u32,
+    /// Pixels per line
     pub stride: u32,
     pub base: u64,
+    /// Bits per pixel, 16 or 32
+    pub bpp: u32,
+    /// Bits of a little endian pixel holding each channel
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+}
+
+impl OsVideoMode {
+    /// Blue, green and red bytes followed by an unused byte
+    pub const BGRX_MASKS: [u32; 3] = [0x00FF_0000, 0x0000_FF00, 0x0000_00FF];
+
+    pub fn bytes_per_pixel(&self) -> u32 {
+        self.bpp.div_ceil(8)
+    }
+
+    /// Name of the pixel format in the style of DRM formats, listing channels from the most
+    /// significant bit, such as `XRGB8888` or `RGB565`
+    pub fn format(&self) -> String {
+        let all = if self.bpp >= 32 {
+            u32::MAX
+        } else {
+            (1 << self.bpp) - 1
+        };
+        let unused = all & !(self.red_mask | self.green_mask | self.blue_mask);
+        let mut channels = [
+            (self.red_mask, 'R'),
+            (self.green_mask, 'G'),
+            (self.blue_mask, 'B'),
+            (unused, 'X'),
+        ];
+        channels.sort_by_key(|channel| cmp::Reverse(channel.0));
+
+        let channels = channels.iter().filter(|(mask, _)| *mask != 0);
+        let mut name: String = channels.clone().map(|(_, letter)| letter).collect();
+        for (mask, _) in channels {
+            write!(name, "{}", mask.count_ones()).unwrap();
+        }
+        name
+    }
 }
 
 pub trait Os {
-    type D: Disk;
+    type D: Disk + CachedDisk;
     type V: Iterator<Item = OsVideoMode>;
 
     fn name(&self) -> &str;
 
     fn alloc_zeroed_page_aligned(&self, size: usize) -> *mut u8;
 
-    #[allow(dead_code)]
     fn page_size(&self) -> usize;
 
+    /// End of the highest region in the firmware memory map, which the linear map of physical
+    /// memory has to reach
+    fn memory_end(&self) -> u64;
+
+    /// Open RedoxFS, preferring devices that match the patterns in order
     fn filesystem(
         &self,
+        devices: &[DeviceMatch],
         password_opt: Option<&[u8]>,
     ) -> syscall::Result<redoxfs::FileSystem<Self::D>>;
+    /// RedoxFS is only available in memory, such as when netbooting, so the kernel must use it
+    /// as a live disk
+    fn live_only(&self) -> bool;
+
+    /// Load a file from the partition this program was loaded from, such as the ESP, into page
+    /// aligned memory. Paths use `/` as the separator.
+    fn boot_file(&self, path: &str) -> Option<&'static mut [u8]>;
 
     fn hwdesc(&self) -> OsHwDesc;
 
+    /// Make devices other than the boot device available before looking for RedoxFS
+    fn connect_devices(&self, connect_all: bool);
+
+    /// Find other operating systems that can be started from the boot menu
+    fn boot_entries(&self) -> Vec<OsBootEntry>;
+    /// Start another operating system, only returning if it failed or exited
+    fn chainload(&self, entry: &OsBootEntry);
+
+    /// Milliseconds from an arbitrary starting point, for measuring durations
+    fn time_ms(&self) -> Option<u64>;
+
     fn video_outputs(&self) -> usize;
     fn video_modes(&self, output_i: usize) -> Self::V;
     fn set_video_mode(&self, output_i: usize, mode: &mut OsVideoMode);
     fn best_resolution(&self, output_i: usize) -> Option<(u32, u32)>;
+    /// EDID of the display connected to an output
+    fn video_edid(&self, output_i: usize) -> Option<Vec<u8>>;
 
     fn get_key(&self) -> OsKey;
+    /// Wait for a key for up to timeout_ms milliseconds
+    fn get_key_timeout(&self, timeout_ms: u64) -> Option<OsKey>;
 
     fn clear_text(&self);
     fn get_text_position(&self) -> (usize, usize);
@@ -1,6 +1,5 @@
 use crate::KernelArgs;
-use crate::arch::PHYS_OFFSET;
-use crate::arch::SATP_BITS;
+use crate::arch::{phys_offset, satp_bits};
 use crate::logger::LOGGER;
 use crate::os::OsEfi;
 use crate::os::uefi::memory_map::memory_map;
@@ -24,7 +23,7 @@ unsafe extern "C" fn kernel_entry(
         asm!(
         "csrw satp, {0}",
         "sfence.vma",
-        in(reg) (page_phys >> 12 | SATP_BITS << 60)
+        in(reg) (page_phys >> 12 | satp_bits() << 60)
         );
 
         let entry_fn: extern "C" fn(*const KernelArgs) -> ! = mem::transmute(func);
@@ -56,7 +55,7 @@ pub fn main() -> Result<()> {
 
         kernel_entry(
             page_phys,
-            args.stack_base + args.stack_size + PHYS_OFFSET,
+            args.stack_base + args.stack_size + phys_offset(),
             func,
             &args,
         );
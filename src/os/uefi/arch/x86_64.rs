@@ -1,13 +1,148 @@
-use core::{arch::asm, mem};
-use uefi::status::Result;
+use core::{
+    arch::{asm, naked_asm},
+    mem, ptr,
+};
+use uefi::{memory::MemoryType, status::Result};
 use x86::{
     controlregs::{self, Cr0, Cr4},
     msr,
 };
 
-use crate::{KernelArgs, logger::LOGGER};
+use crate::os::{OsMemoryEntry, OsMemoryKind};
+use crate::{KernelArgs, area_add, logger::LOGGER};
+
+use super::super::{OsEfi, memory_map::memory_map, status_to_result};
+
+// Segments for switching to five-level paging, marked as accessed so they are not written to
+static LA57_GDT: [u64; 4] = [
+    // Null
+    0,
+    // 64-bit code
+    0x00AF_9B00_0000_FFFF,
+    // 32-bit code
+    0x00CF_9B00_0000_FFFF,
+    // Data
+    0x00CF_9300_0000_FFFF,
+];
+
+/// Parameters of la57_entry, with offsets used in its assembly
+#[repr(C, packed)]
+struct La57Entry {
+    gdtr_limit: u16,
+    gdtr_base: u64,
+    // Far pointer to the 64-bit code, filled in by la57_entry
+    code64_offset: u32,
+    code64_selector: u16,
+    page_table: u32,
+    stack: u64,
+    func: u64,
+    args: u64,
+}
+
+static mut LA57_ENTRY: La57Entry = La57Entry {
+    gdtr_limit: 0,
+    gdtr_base: 0,
+    code64_offset: 0,
+    code64_selector: 0x08,
+    page_table: 0,
+    stack: 0,
+    func: 0,
+    args: 0,
+};
+
+/// CR4.LA57 can only be changed with paging disabled, so this switches to 32-bit code, disables
+/// paging, enables five-level paging and returns to long mode before calling the kernel. The code,
+/// the GDT and the parameters must be identity mapped below 4 GiB.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn la57_entry(entry: *mut La57Entry) -> ! {
+    naked_asm!(
+        "
+    lgdt [rdi]
+
+    // Fill in the far pointer to the 64-bit code
+    lea rax, [rip + 3f]
+    mov [rdi + 10], eax
+
+    // Far return to the 32-bit code segment
+    lea rax, [rip + 2f]
+    push 0x10
+    push rax
+    retfq
+
+.code32
+2:
+    mov ax, 0x18
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
 
-use super::super::{OsEfi, memory_map::memory_map};
+    // Disable paging, which leaves long mode
+    mov eax, cr0
+    and eax, 0x7FFFFFFF
+    mov cr0, eax
+
+    // Enable five-level paging
+    mov eax, cr4
+    or eax, 1 << 12
+    mov cr4, eax
+
+    // Set PML5, then enable paging with write protection, which enters long mode again as
+    // EFER.LME is still set
+    mov eax, [edi + 16]
+    mov cr3, eax
+    mov eax, cr0
+    or eax, 0x80010000
+    mov cr0, eax
+
+    // Far jump to the 64-bit code segment
+    jmp fword ptr [edi + 10]
+
+.code64
+3:
+    // The upper half of registers is undefined after 32-bit code
+    mov edi, edi
+    mov rsp, [rdi + 20]
+    mov rax, [rdi + 28]
+    mov rdi, [rdi + 36]
+    call rax
+4:
+    cli
+    hlt
+    jmp 4b
+"
+    );
+}
+
+/// Check that la57_entry, its parameters and the GDT are below 4 GiB, as five-level paging is
+/// enabled from 32-bit code
+pub fn la57_entry_below_4g() -> bool {
+    (la57_entry as *const () as usize) < 0x1_0000_0000
+        && (&raw const LA57_ENTRY as usize) < 0x1_0000_0000
+        && (LA57_GDT.as_ptr() as usize) < 0x1_0000_0000
+}
+
+/// Copy the PML5 below 4 GiB, as CR3 is loaded from 32-bit code to enable five-level paging
+fn la57_page_table(page_phys: usize) -> usize {
+    if page_phys < 0x1_0000_0000 {
+        return page_phys;
+    }
+
+    let mut low_phys = 0xFFFF_FFFF;
+    status_to_result((std::system_table().BootServices.AllocatePages)(
+        1, // AllocateMaxAddress
+        MemoryType::EfiRuntimeServicesData,
+        1,
+        &mut low_phys,
+    ))
+    .expect("Failed to allocate PML5 below 4 GiB");
+    unsafe { ptr::copy(page_phys as *const u8, low_phys as *mut u8, 4096) };
+    area_add(OsMemoryEntry {
+        base: low_phys as u64,
+        size: 4096,
+        kind: OsMemoryKind::Reclaim,
+    });
+    low_phys
+}
 
 unsafe extern "C" fn kernel_entry(
     page_phys: usize,
@@ -19,6 +154,8 @@ unsafe extern "C" fn kernel_entry(
         // Read memory map and exit boot services
         memory_map().exit_boot_services();
 
+        crate::arch::pat_init();
+
         // Enable FXSAVE/FXRSTOR, Page Global, Page Address Extension, and Page Size Extension
         let mut cr4 = controlregs::cr4();
         cr4 |= Cr4::CR4_ENABLE_SSE
@@ -32,6 +169,17 @@ unsafe extern "C" fn kernel_entry(
         efer |= 1 << 11 | 1 << 8;
         msr::wrmsr(msr::IA32_EFER, efer);
 
+        if crate::arch::LA57 {
+            let entry = &raw mut LA57_ENTRY;
+            (*entry).gdtr_limit = (mem::size_of_val(&LA57_GDT) - 1) as u16;
+            (*entry).gdtr_base = LA57_GDT.as_ptr() as u64;
+            (*entry).page_table = page_phys as u32;
+            (*entry).stack = stack;
+            (*entry).func = func;
+            (*entry).args = args as u64;
+            la57_entry(entry);
+        }
+
         // Set new page map
         controlregs::cr3_write(page_phys as u64);
 
@@ -58,6 +206,11 @@ pub fn main() -> Result<()> {
     let _ = (os.st.ConsoleOut.EnableCursor)(os.st.ConsoleOut, false);
 
     let (page_phys, func, args) = crate::main(&mut os);
+    let page_phys = if unsafe { crate::arch::LA57 } {
+        la57_page_table(page_phys)
+    } else {
+        page_phys
+    };
 
     unsafe {
         kernel_entry(
@@ -0,0 +1,171 @@
+use alloc::{format, string::String, vec::Vec};
+use core::{mem, ptr};
+use uefi::{
+    Handle,
+    device::{DevicePath, DevicePathMediaType, DevicePathType},
+    fs::FILE_DIRECTORY,
+    status::{Result, Status},
+};
+use uefi_std::{
+    ffi::{nstr, wstr},
+    fs::{Dir, FileSystem},
+    loaded_image::LoadedImage,
+    proto::Protocol,
+};
+
+use crate::os::OsBootEntry;
+
+use super::{
+    boot_services_full,
+    device::esp_device,
+    device_path::{DevicePathIter, device_path_to_string},
+    status_to_result,
+};
+
+pub fn read_dir(dir: &mut Dir) -> Vec<(String, bool)> {
+    let mut entries = Vec::new();
+    loop {
+        match dir.read() {
+            Ok(Some(info)) => {
+                let name = unsafe { nstr(info.FileName.as_ptr()) };
+                if name != "." && name != ".." {
+                    entries.push((name, info.Attribute & FILE_DIRECTORY != 0));
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                log::warn!("Failed to read ESP directory: {:?}", err);
+                break;
+            }
+        }
+    }
+    entries
+}
+
+/// Find EFI applications in `EFI/<vendor>` directories on the ESP, excluding this one
+pub fn esp_boot_entries() -> Vec<OsBootEntry> {
+    let mut entries = Vec::new();
+
+    let Some((esp_handle, _esp_device_path)) = esp_device() else {
+        return entries;
+    };
+    let Ok(mut esp_fs) = FileSystem::handle_protocol(esp_handle) else {
+        return entries;
+    };
+    let Ok(mut root) = esp_fs.root() else {
+        return entries;
+    };
+    let Ok(mut efi_dir) = root.open_dir(&wstr("EFI")) else {
+        return entries;
+    };
+
+    // Path of this program, such as /EFI/BOOT/BOOTX64.EFI
+    let own_path = match LoadedImage::handle_protocol(std::handle()) {
+        Ok(loaded_image) if loaded_image.0.FilePath != 0 => {
+            device_path_to_string(unsafe { &*(loaded_image.0.FilePath as *const DevicePath) })
+        }
+        _ => String::new(),
+    };
+
+    let mut vendors = read_dir(&mut efi_dir);
+    vendors.sort();
+    for (vendor, is_dir) in vendors {
+        if !is_dir {
+            continue;
+        }
+
+        let Ok(mut vendor_dir) = efi_dir.open_dir(&wstr(&vendor)) else {
+            continue;
+        };
+        let mut files = read_dir(&mut vendor_dir);
+        files.sort();
+        for (file, is_dir) in files {
+            if is_dir || !file.to_ascii_lowercase().ends_with(".efi") {
+                continue;
+            }
+
+            let path = format!("EFI/{}/{}", vendor, file);
+            if own_path.trim_start_matches('/').eq_ignore_ascii_case(&path) {
+                continue;
+            }
+
+            entries.push(OsBootEntry {
+                name: format!("{}/{}", vendor, file),
+                path,
+            });
+        }
+    }
+
+    entries
+}
+
+/// Build a device path for a file on the ESP
+fn file_device_path(esp_device_path: &DevicePath, path: &str) -> Vec<u8> {
+    let mut device_path = Vec::new();
+
+    for (node, node_data) in DevicePathIter::new(esp_device_path) {
+        device_path.extend_from_slice(&[node.Type, node.SubType]);
+        device_path.extend_from_slice(&node.Length.to_le_bytes());
+        device_path.extend_from_slice(node_data);
+    }
+
+    let file_path = wstr(&format!("\\{}", path.replace('/', "\\")));
+    let length = mem::size_of::<DevicePath>() + file_path.len() * 2;
+    device_path.extend_from_slice(&[
+        DevicePathType::Media as u8,
+        DevicePathMediaType::Filepath as u8,
+    ]);
+    device_path.extend_from_slice(&(length as u16).to_le_bytes());
+    for c in file_path {
+        device_path.extend_from_slice(&c.to_le_bytes());
+    }
+
+    // End of entire device path
+    device_path.extend_from_slice(&[DevicePathType::End as u8, 0xFF, 4, 0]);
+
+    device_path
+}
+
+/// Load and start an EFI application or driver from the ESP, returning when it exits
+pub fn start_image(path: &str) -> Result<()> {
+    let Some((_esp_handle, esp_device_path)) = esp_device() else {
+        return Err(Status::NOT_FOUND);
+    };
+
+    let device_path = file_device_path(esp_device_path.0, path);
+    log::info!(
+        "Starting {}",
+        device_path_to_string(unsafe { &*(device_path.as_ptr() as *const DevicePath) })
+    );
+
+    let boot_services = &std::system_table().BootServices;
+    let mut image_handle = Handle(0);
+    let status = (boot_services.LoadImage)(
+        false,
+        std::handle(),
+        device_path.as_ptr() as usize,
+        ptr::null(),
+        0,
+        &mut image_handle,
+    );
+    if status == Status::SECURITY_VIOLATION && image_handle != Handle(0) {
+        // The image was loaded but fails the security policy, so it must not be started
+        let _ = (boot_services_full().UnloadImage)(image_handle);
+    }
+    status_to_result(status)?;
+
+    let mut exit_data_size = 0;
+    let mut exit_data = ptr::null_mut();
+    let res = status_to_result((boot_services.StartImage)(
+        image_handle,
+        &mut exit_data_size,
+        &mut exit_data,
+    ));
+    log::debug!("{} exited with {:?}", path, res);
+    if res.is_err() {
+        // Images that could not be started stay loaded. Images that exited are already unloaded,
+        // which only makes this fail.
+        let _ = (boot_services_full().UnloadImage)(image_handle);
+    }
+    res.map(|_| ())
+}
@@ -1,17 +1,21 @@
-use alloc::{string::String, vec, vec::Vec};
-use core::{fmt::Write, mem, ptr, slice};
-use uefi::{
-    Handle,
-    device::{
-        DevicePath, DevicePathAcpiType, DevicePathBbsType, DevicePathEndType,
-        DevicePathHardwareType, DevicePathMediaType, DevicePathMessagingType, DevicePathType,
+use alloc::{vec, vec::Vec};
+use core::{cmp, slice};
+use uefi::{Handle, device::DevicePath, guid::Guid, status::Status};
+use uefi_std::{ffi::wstr, fs::FileSystem, loaded_image::LoadedImage, proto::Protocol};
+
+use crate::config::DeviceMatch;
+use crate::disk_cache::DiskCache;
+use crate::os::Os;
+use crate::progress::Progress;
+
+use super::{
+    OsEfi,
+    device_path::{
+        DevicePathIter, device_path_contains, device_path_pattern, device_path_to_string,
     },
-    guid::Guid,
-    status::Status,
+    disk::{DiskEfi, DiskOrFileEfi},
+    pxe::pxe_live_image,
 };
-use uefi_std::{fs::FileSystem, loaded_image::LoadedImage, proto::Protocol};
-
-use super::disk::{DiskEfi, DiskOrFileEfi};
 
 #[derive(Debug)]
 enum DevicePathRelation {
@@ -46,11 +50,18 @@ fn device_path_relation(a_path: &DevicePath, b_path: &DevicePath) -> DevicePathR
     }
 }
 
-fn esp_live_image(esp_handle: Handle, esp_device_path: &DevicePath) -> Option<Vec<u8>> {
+/// Read a file from the ESP into page aligned memory, using `\` as the path separator
+fn esp_file(
+    os: &OsEfi,
+    esp_handle: Handle,
+    esp_device_path: &DevicePath,
+    path: &str,
+) -> Option<&'static mut [u8]> {
     let mut esp_fs = match FileSystem::handle_protocol(esp_handle) {
         Ok(esp_fs) => esp_fs,
         Err(err) => {
-            log::warn!("Failed to find SimpleFileSystem protocol: {:?}", err);
+            // Expected when netbooting, as the boot device is a network interface
+            log::debug!("Failed to find SimpleFileSystem protocol: {:?}", err);
             return None;
         }
     };
@@ -63,84 +74,147 @@ fn esp_live_image(esp_handle: Handle, esp_device_path: &DevicePath) -> Option<Ve
         }
     };
 
-    const fn as_utf16_str<const N: usize>(s: [u8; N]) -> [u16; N] {
-        let mut ret = [0; N];
-        let mut i = 0;
-        while i < N {
-            ret[i] = s[i] as u16;
-            i += 1;
-        }
-        ret
-    }
-
-    let filename = const { &as_utf16_str(*b"redox-live.iso\0") };
-    let mut live_image = match root.open(filename) {
-        Ok(live_image) => live_image,
+    let mut file = match root.open(&wstr(path)) {
+        Ok(file) => file,
         Err(Status::NOT_FOUND) => return None,
         Err(err) => {
             log::warn!(
-                "Failed to open {}\\redox-live.iso: {:?}",
+                "Failed to open {}\\{}: {:?}",
                 device_path_to_string(esp_device_path),
+                path,
                 err
             );
             return None;
         }
     };
 
-    let mut buffer = Vec::new();
+    let size = match file.info() {
+        Ok(info) => info.FileSize,
+        Err(err) => {
+            log::warn!("Failed to get size of {}: {:?}", path, err);
+            return None;
+        }
+    };
+    if size == 0 {
+        log::warn!("{} is empty", path);
+        return None;
+    }
 
-    live_image.read_to_end(&mut buffer).unwrap();
+    // Read directly into page aligned memory so it can be used without a copy
+    let ptr = os.alloc_zeroed_page_aligned(size as usize);
+    if ptr.is_null() {
+        log::warn!("Failed to allocate memory for {}", path);
+        return None;
+    }
+    let buffer = unsafe { slice::from_raw_parts_mut(ptr, size as usize) };
+
+    // Small files such as configuration are read without showing progress
+    let progress_opt = (size >= crate::MIBI as u64).then(|| Progress::new(os, path, size));
+    let mut i = 0;
+    while i < buffer.len() {
+        let end = cmp::min(i + crate::MIBI, buffer.len());
+        match file.read(&mut buffer[i..end]) {
+            Ok(0) => {
+                log::warn!("{} ended at {} of {} bytes", path, i, size);
+                return None;
+            }
+            Ok(count) => i += count,
+            Err(err) => {
+                log::warn!("Failed to read {}: {:?}", path, err);
+                return None;
+            }
+        }
+        if let Some(progress) = &progress_opt {
+            progress.update(i as u64, i as u64);
+        }
+    }
+    if let Some(progress) = &progress_opt {
+        progress.finish(size, size);
+    }
 
     Some(buffer)
 }
 
-pub struct DiskDevice {
-    pub handle: Handle,
-    pub disk: DiskOrFileEfi,
-    pub partition_offset: u64,
-    pub device_path: DevicePathProtocol,
-    pub file_path: Option<&'static str>,
-}
-
-pub fn disk_device_priority() -> Vec<DiskDevice> {
-    // Get the handle of the partition this program was loaded from, which should be the ESP
+/// Get the handle and device path of the partition this program was loaded from, which should
+/// be the ESP
+pub fn esp_device() -> Option<(Handle, DevicePathProtocol)> {
     let esp_handle = match LoadedImage::handle_protocol(std::handle()) {
         Ok(loaded_image) => loaded_image.0.DeviceHandle,
         Err(err) => {
             log::warn!("Failed to find LoadedImage protocol: {:?}", err);
-            return Vec::new();
+            return None;
         }
     };
 
-    // Get the device path of the ESP
-    let esp_device_path = match DevicePathProtocol::handle_protocol(esp_handle) {
-        Ok(ok) => ok,
+    match DevicePathProtocol::handle_protocol(esp_handle) {
+        Ok(esp_device_path) => Some((esp_handle, esp_device_path)),
         Err(err) => {
             log::warn!(
                 "Failed to find device path protocol on {:?}: {:?}",
                 esp_handle,
                 err
             );
-            return Vec::new();
+            None
         }
+    }
+}
+
+/// Read a file from the ESP, with `/` as the path separator
+pub fn esp_boot_file(os: &OsEfi, path: &str) -> Option<&'static mut [u8]> {
+    let (esp_handle, esp_device_path) = esp_device()?;
+    let path = path.replace('/', "\\");
+    esp_file(os, esp_handle, esp_device_path.0, &path)
+}
+
+pub struct DiskDevice {
+    pub handle: Handle,
+    pub disk: DiskOrFileEfi,
+    pub partition_offset: u64,
+    pub device_path: DevicePathProtocol,
+    pub file_path: Option<&'static str>,
+}
+
+pub fn disk_device_priority(os: &OsEfi, matches: &[DeviceMatch]) -> Vec<DiskDevice> {
+    let (esp_handle, esp_device_path) = match esp_device() {
+        Some(some) => some,
+        None => return Vec::new(),
     };
 
     if cfg!(feature = "live") {
-        // First try to get a live image from redox-live.iso. This is required to support netbooting.
-        if let Some(buffer) = esp_live_image(esp_handle, esp_device_path.0) {
-            return vec![DiskDevice {
-                handle: esp_handle,
-                // Support both a copy of livedisk.iso and a standalone redoxfs partition
-                partition_offset: if &buffer[512..520] == b"EFI PART" {
-                    //TODO: get block from partition table
-                    2 * crate::MIBI as u64
-                } else {
-                    0
-                },
-                disk: DiskOrFileEfi::File(buffer),
-                device_path: esp_device_path,
-                file_path: Some("redox-live.iso"),
-            }];
+        // First try to get a live image from redox-live.iso on the ESP or the PXE boot server.
+        // This is required to support netbooting.
+        let live_image_opt = esp_file(os, esp_handle, esp_device_path.0, "redox-live.iso")
+            .map(|live_image| &*live_image)
+            .or_else(|| pxe_live_image(os, esp_handle));
+        if let Some(buffer) = live_image_opt {
+            // Support both a copy of livedisk.iso and a standalone redoxfs partition
+            let partition_offset = if buffer.get(512..520) == Some(b"EFI PART") {
+                //TODO: get block from partition table
+                2 * crate::MIBI as u64
+            } else {
+                0
+            };
+
+            match buffer.get(partition_offset as usize..) {
+                Some(live) => {
+                    // The image is already in memory, so live mode can use it without copying
+                    unsafe {
+                        crate::LIVE_OPT = Some((partition_offset / redoxfs::BLOCK_SIZE, live));
+                    }
+
+                    return vec![DiskDevice {
+                        handle: esp_handle,
+                        partition_offset,
+                        disk: DiskOrFileEfi::File(buffer),
+                        device_path: esp_device_path,
+                        file_path: Some("redox-live.iso"),
+                    }];
+                }
+                None => log::warn!(
+                    "Live image of {} bytes ends before its partition, ignoring it",
+                    buffer.len()
+                ),
+            }
         }
     }
 
@@ -166,7 +240,7 @@ pub fn disk_device_priority() -> Vec<DiskDevice> {
             }
         };
 
-        if !disk.0.Media.MediaPresent {
+        if !disk.block_io.Media.MediaPresent {
             continue;
         }
 
@@ -184,13 +258,13 @@ pub fn disk_device_priority() -> Vec<DiskDevice> {
 
         devices.push(DiskDevice {
             handle,
-            partition_offset: if disk.0.Media.LogicalPartition {
+            partition_offset: if disk.block_io.Media.LogicalPartition {
                 0
             } else {
                 //TODO: get block from partition table
                 2 * crate::MIBI as u64
             },
-            disk: DiskOrFileEfi::Disk(disk),
+            disk: DiskOrFileEfi::Disk(DiskCache::new(disk.with_handle(handle))),
             device_path,
             file_path: None,
         });
@@ -236,216 +310,36 @@ pub fn disk_device_priority() -> Vec<DiskDevice> {
     // Add any remaining devices
     priority.extend(devices);
 
-    priority
-}
-
-#[repr(C, packed)]
-#[allow(dead_code)]
-struct DevicePathHarddrive {
-    partition_number: u32,
-    partition_start: u64,
-    partition_size: u64,
-    partition_signature: [u8; 16],
-    partition_format: u8,
-    signature_type: u8,
-}
-
-pub fn device_path_to_string(device_path: &DevicePath) -> String {
-    let mut s = String::new();
-    for (node, node_data) in DevicePathIter::new(device_path) {
-        let read_u16 = |i: usize| -> u16 { (node_data[i] as u16) | (node_data[i + 1] as u16) << 8 };
-
-        let read_u32 = |i: usize| -> u32 {
-            (node_data[i] as u32)
-                | (node_data[i + 1] as u32) << 8
-                | (node_data[i + 2] as u32) << 16
-                | (node_data[i + 3] as u32) << 24
-        };
-
-        if !s.is_empty() {
-            s.push('/');
-        }
-
-        let _ = match DevicePathType::try_from(node.Type) {
-            Ok(path_type) => match path_type {
-                DevicePathType::Hardware => match DevicePathHardwareType::try_from(node.SubType) {
-                    Ok(sub_type) => match sub_type {
-                        DevicePathHardwareType::Pci if node_data.len() == 2 => {
-                            let func = node_data[0];
-                            let dev = node_data[1];
-                            write!(s, "Pci(0x{dev:X},0x{func:X})")
-                        }
-                        _ => write!(s, "{path_type:?} {sub_type:?} {node_data:X?}"),
-                    },
-                    Err(()) => write!(s, "{:?} 0x{:02X} {:X?}", path_type, node.SubType, node_data),
-                },
-                DevicePathType::Acpi => match DevicePathAcpiType::try_from(node.SubType) {
-                    Ok(sub_type) => match sub_type {
-                        DevicePathAcpiType::Acpi if node_data.len() == 8 => {
-                            let hid = read_u32(0);
-                            let uid = read_u32(4);
-                            if hid & 0xFFFF == 0x41D0 {
-                                write!(s, "Acpi(PNP{:04X},0x{:X})", hid >> 16, uid)
-                            } else {
-                                write!(s, "Acpi(0x{hid:08X},0x{uid:X})")
-                            }
-                        }
-                        _ => write!(s, "{path_type:?} {sub_type:?} {node_data:X?}"),
-                    },
-                    Err(()) => write!(s, "{:?} 0x{:02X} {:X?}", path_type, node.SubType, node_data),
-                },
-                DevicePathType::Messaging => {
-                    match DevicePathMessagingType::try_from(node.SubType) {
-                        Ok(sub_type) => match sub_type {
-                            DevicePathMessagingType::Sata if node_data.len() == 6 => {
-                                let hba_port = read_u16(0);
-                                let multiplier_port = read_u16(2);
-                                let logical_unit = read_u16(4);
-                                if multiplier_port & (1 << 15) != 0 {
-                                    write!(s, "Sata(0x{hba_port:X},0x{logical_unit:X})")
-                                } else {
-                                    write!(
-                                        s,
-                                        "Sata(0x{hba_port:X},0x{multiplier_port:X},0x{logical_unit:X})"
-                                    )
-                                }
-                            }
-                            DevicePathMessagingType::Usb if node_data.len() == 2 => {
-                                let port = node_data[0];
-                                let iface = node_data[1];
-                                write!(s, "Usb(0x{port:X},0x{iface:X})")
-                            }
-                            DevicePathMessagingType::Nvme if node_data.len() == 12 => {
-                                let nsid = read_u32(0);
-                                let eui = &node_data[4..];
-                                if eui == [0, 0, 0, 0, 0, 0, 0, 0] {
-                                    write!(s, "NVMe(0x{nsid:X})")
-                                } else {
-                                    write!(
-                                        s,
-                                        "NVMe(0x{:X},{:02X}-{:02X}-{:02X}-{:02X}-{:02X}-{:02X}-{:02X}-{:02X})",
-                                        nsid,
-                                        eui[0],
-                                        eui[1],
-                                        eui[2],
-                                        eui[3],
-                                        eui[4],
-                                        eui[5],
-                                        eui[6],
-                                        eui[7],
-                                    )
-                                }
-                            }
-                            DevicePathMessagingType::Mac
-                                if node_data.len() == 33 && node_data[32] == 0
-                                    || node_data[32] == 1 =>
-                            {
-                                write!(
-                                    s,
-                                    "Mac({:02x}{:02x}{:02x}{:02x}{:02x}{:02x},{:#02x})",
-                                    node_data[0],
-                                    node_data[1],
-                                    node_data[2],
-                                    node_data[3],
-                                    node_data[4],
-                                    node_data[5],
-                                    node_data[32],
-                                )
-                            }
-                            _ => write!(s, "{path_type:?} {sub_type:?} {node_data:X?}"),
-                        },
-                        Err(()) => {
-                            write!(s, "{:?} 0x{:02X} {:X?}", path_type, node.SubType, node_data)
-                        }
-                    }
-                }
-                DevicePathType::Media => match DevicePathMediaType::try_from(node.SubType) {
-                    Ok(sub_type) => {
-                        match sub_type {
-                            DevicePathMediaType::Harddrive
-                                if node_data.len() == mem::size_of::<DevicePathHarddrive>() =>
-                            {
-                                let harddrive = unsafe {
-                                    ptr::read(node_data.as_ptr() as *const DevicePathHarddrive)
-                                };
-                                let partition_number = unsafe {
-                                    ptr::read_unaligned(ptr::addr_of!(harddrive.partition_number))
-                                };
-                                match harddrive.signature_type {
-                                    1 => {
-                                        let id = unsafe {
-                                            ptr::read(harddrive.partition_signature.as_ptr()
-                                                as *const u32)
-                                        };
-                                        write!(s, "HD(0x{partition_number:X},MBR,0x{id:X})")
-                                    }
-                                    2 => {
-                                        let guid = unsafe {
-                                            ptr::read(harddrive.partition_signature.as_ptr()
-                                                as *const Guid)
-                                        };
-                                        write!(
-                                            s,
-                                            "HD(0x{:X},GPT,{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X})",
-                                            partition_number,
-                                            guid.0,
-                                            guid.1,
-                                            guid.2,
-                                            guid.3[0],
-                                            guid.3[1],
-                                            guid.3[2],
-                                            guid.3[3],
-                                            guid.3[4],
-                                            guid.3[5],
-                                            guid.3[6],
-                                            guid.3[7],
-                                        )
-                                    }
-                                    _ => {
-                                        write!(
-                                            s,
-                                            "HD(0x{:X},0x{:X},{:X?})",
-                                            partition_number,
-                                            harddrive.signature_type,
-                                            harddrive.partition_signature
-                                        )
-                                    }
-                                }
-                            }
-                            DevicePathMediaType::Filepath => {
-                                for chunk in node_data.chunks_exact(2) {
-                                    let data = (chunk[0] as u16) | (chunk[1] as u16) << 8;
-                                    match unsafe { char::from_u32_unchecked(data as u32) } {
-                                        '\\' => s.push('/'),
-                                        c => s.push(c),
-                                    }
-                                }
-                                Ok(())
-                            }
-                            _ => write!(s, "{path_type:?} {sub_type:?} {node_data:X?}"),
-                        }
-                    }
-                    Err(()) => write!(s, "{:?} 0x{:02X} {:X?}", path_type, node.SubType, node_data),
-                },
-                DevicePathType::Bbs => match DevicePathBbsType::try_from(node.SubType) {
-                    Ok(sub_type) => write!(s, "{path_type:?} {sub_type:?} {node_data:X?}"),
-                    Err(()) => write!(s, "{:?} 0x{:02X} {:X?}", path_type, node.SubType, node_data),
-                },
-                DevicePathType::End => match DevicePathEndType::try_from(node.SubType) {
-                    Ok(sub_type) => write!(s, "{path_type:?} {sub_type:?} {node_data:X?}"),
-                    Err(()) => write!(s, "{:?} 0x{:02X} {:X?}", path_type, node.SubType, node_data),
-                },
-            },
-            Err(()) => {
-                write!(
-                    s,
-                    "0x{:02X} 0x{:02X} {:X?}",
-                    node.Type, node.SubType, node_data
-                )
-            }
-        };
+    // Configured device paths come first, in the configured order
+    if matches
+        .iter()
+        .any(|device| matches!(device, DeviceMatch::DevicePath(_)))
+    {
+        // Patterns that parse as device paths are compared node by node, others as text
+        let patterns: Vec<Option<Vec<u8>>> = matches
+            .iter()
+            .map(|device| match device {
+                DeviceMatch::DevicePath(pattern) => device_path_pattern(pattern),
+                DeviceMatch::Uuid(_) => None,
+            })
+            .collect();
+
+        priority.sort_by_cached_key(|device| {
+            let device_path = device_path_to_string(device.device_path.0);
+            matches
+                .iter()
+                .zip(&patterns)
+                .position(|(device_match, pattern_opt)| match pattern_opt {
+                    Some(pattern) => device_path_contains(device.device_path.0, unsafe {
+                        &*(pattern.as_ptr() as *const DevicePath)
+                    }),
+                    None => device_match.matches_device_path(&device_path),
+                })
+                .unwrap_or(matches.len())
+        });
     }
-    s
+
+    priority
 }
 
 pub struct DevicePathProtocol(pub &'static mut DevicePath);
@@ -471,39 +365,3 @@ impl Protocol<DevicePath> for LoadedImageDevicePathProtocol {
         Self(inner)
     }
 }
-
-pub struct DevicePathIter<'a> {
-    device_path: &'a DevicePath,
-    node_ptr: *const DevicePath,
-}
-
-impl<'a> DevicePathIter<'a> {
-    pub fn new(device_path: &'a DevicePath) -> Self {
-        Self {
-            device_path,
-            node_ptr: device_path as *const DevicePath,
-        }
-    }
-}
-
-impl<'a> Iterator for DevicePathIter<'a> {
-    type Item = (&'a DevicePath, &'a [u8]);
-    fn next(&mut self) -> Option<Self::Item> {
-        let node = unsafe { &*self.node_ptr };
-
-        if node.Type == DevicePathType::End as u8 {
-            return None;
-        }
-
-        let node_data = unsafe {
-            slice::from_raw_parts(
-                self.node_ptr.add(1) as *mut u8,
-                node.Length.saturating_sub(4) as usize,
-            )
-        };
-
-        self.node_ptr = (self.node_ptr as usize + node.Length as usize) as *const DevicePath;
-
-        Some((node, node_data))
-    }
-}
@@ -0,0 +1,795 @@
+// Text form of device paths, based on the UEFI specification's device path to text conversion
+
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, ptr, slice};
+use uefi::device::{
+    DevicePath, DevicePathAcpiType, DevicePathEndType, DevicePathHardwareType, DevicePathMediaType,
+    DevicePathMessagingType, DevicePathType,
+};
+
+const HW_PCI: (u8, u8) = (
+    DevicePathType::Hardware as u8,
+    DevicePathHardwareType::Pci as u8,
+);
+const HW_MEMMAP: (u8, u8) = (
+    DevicePathType::Hardware as u8,
+    DevicePathHardwareType::Memmap as u8,
+);
+const HW_VENDOR: (u8, u8) = (
+    DevicePathType::Hardware as u8,
+    DevicePathHardwareType::Vendor as u8,
+);
+const HW_CONTROLLER: (u8, u8) = (
+    DevicePathType::Hardware as u8,
+    DevicePathHardwareType::Controller as u8,
+);
+const ACPI_ACPI: (u8, u8) = (DevicePathType::Acpi as u8, DevicePathAcpiType::Acpi as u8);
+const MSG_SCSI: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Scsi as u8,
+);
+const MSG_USB: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Usb as u8,
+);
+const MSG_VENDOR: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Vendordefined as u8,
+);
+const MSG_MAC: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Mac as u8,
+);
+const MSG_IPV4: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Ipv4 as u8,
+);
+const MSG_IPV6: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Ipv6 as u8,
+);
+const MSG_SATA: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Sata as u8,
+);
+const MSG_NVME: (u8, u8) = (
+    DevicePathType::Messaging as u8,
+    DevicePathMessagingType::Nvme as u8,
+);
+// Not defined by the uefi crate
+const MSG_URI: (u8, u8) = (DevicePathType::Messaging as u8, 0x18);
+const MSG_SD: (u8, u8) = (DevicePathType::Messaging as u8, 0x1A);
+const MSG_EMMC: (u8, u8) = (DevicePathType::Messaging as u8, 0x1D);
+const MEDIA_HARDDRIVE: (u8, u8) = (
+    DevicePathType::Media as u8,
+    DevicePathMediaType::Harddrive as u8,
+);
+const MEDIA_CDROM: (u8, u8) = (
+    DevicePathType::Media as u8,
+    DevicePathMediaType::Cdrom as u8,
+);
+const MEDIA_VENDOR: (u8, u8) = (
+    DevicePathType::Media as u8,
+    DevicePathMediaType::Vendor as u8,
+);
+const MEDIA_FILEPATH: (u8, u8) = (
+    DevicePathType::Media as u8,
+    DevicePathMediaType::Filepath as u8,
+);
+
+// ACPI hardware IDs are compressed EISA IDs, PNP is 0x41D0
+const PNP_EISA_ID: u32 = 0x41D0;
+const PNP_PCI_ROOT: u32 = 0x0A03;
+const PNP_PCIE_ROOT: u32 = 0x0A08;
+
+const IP_PROTOCOL_TCP: u16 = 6;
+const IP_PROTOCOL_UDP: u16 = 17;
+
+const IPV6_ORIGINS: [&str; 3] = ["Static", "StatelessAutoConfigure", "StatefulAutoConfigure"];
+
+pub struct DevicePathIter<'a> {
+    device_path: &'a DevicePath,
+    node_ptr: *const DevicePath,
+}
+
+impl<'a> DevicePathIter<'a> {
+    pub fn new(device_path: &'a DevicePath) -> Self {
+        Self {
+            device_path,
+            node_ptr: device_path as *const DevicePath,
+        }
+    }
+}
+
+impl<'a> Iterator for DevicePathIter<'a> {
+    // Nodes are copied, as they are only byte aligned after nodes with an odd length
+    type Item = (DevicePath, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = unsafe {
+            DevicePath {
+                Type: ptr::read_unaligned(ptr::addr_of!((*self.node_ptr).Type)),
+                SubType: ptr::read_unaligned(ptr::addr_of!((*self.node_ptr).SubType)),
+                Length: ptr::read_unaligned(ptr::addr_of!((*self.node_ptr).Length)),
+            }
+        };
+
+        if node.Type == DevicePathType::End as u8 {
+            return None;
+        }
+
+        let node_data = unsafe {
+            slice::from_raw_parts(
+                self.node_ptr.add(1) as *mut u8,
+                node.Length.saturating_sub(4) as usize,
+            )
+        };
+
+        self.node_ptr = (self.node_ptr as usize + node.Length as usize) as *const DevicePath;
+
+        Some((node, node_data))
+    }
+}
+
+/// Little endian reads from node data, which is not aligned
+struct NodeData<'a>(&'a [u8]);
+
+impl NodeData<'_> {
+    fn u16(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.0[i], self.0[i + 1]])
+    }
+
+    fn u32(&self, i: usize) -> u32 {
+        u32::from_le_bytes([self.0[i], self.0[i + 1], self.0[i + 2], self.0[i + 3]])
+    }
+
+    fn u64(&self, i: usize) -> u64 {
+        self.u32(i) as u64 | (self.u32(i + 4) as u64) << 32
+    }
+}
+
+fn write_hex(s: &mut String, data: &[u8]) {
+    for b in data {
+        let _ = write!(s, "{:02X}", b);
+    }
+}
+
+/// Write a GUID stored in the mixed endian EFI_GUID layout
+fn write_guid(s: &mut String, guid: &[u8]) {
+    let _ = write!(
+        s,
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+        u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]),
+        u16::from_le_bytes([guid[4], guid[5]]),
+        u16::from_le_bytes([guid[6], guid[7]]),
+        guid[8],
+        guid[9]
+    );
+    write_hex(s, &guid[10..16]);
+}
+
+fn write_ipv4(s: &mut String, ip: &[u8]) {
+    let _ = write!(s, "{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+}
+
+fn write_ipv6(s: &mut String, ip: &[u8]) {
+    for (i, group) in ip.chunks_exact(2).enumerate() {
+        if i > 0 {
+            s.push(':');
+        }
+        let _ = write!(s, "{:x}", u16::from_be_bytes([group[0], group[1]]));
+    }
+}
+
+fn write_ip_protocol(s: &mut String, protocol: u16) {
+    let _ = match protocol {
+        IP_PROTOCOL_TCP => write!(s, "TCP"),
+        IP_PROTOCOL_UDP => write!(s, "UDP"),
+        _ => write!(s, "0x{:X}", protocol),
+    };
+}
+
+/// Write a vendor node, which has a GUID followed by vendor specific data
+fn write_vendor(s: &mut String, name: &str, data: &[u8]) {
+    s.push_str(name);
+    s.push('(');
+    write_guid(s, &data[..16]);
+    if data.len() > 16 {
+        s.push(',');
+        write_hex(s, &data[16..]);
+    }
+    s.push(')');
+}
+
+/// Write a single node, returning false if its data does not match the expected size
+fn write_node(s: &mut String, kind: (u8, u8), data: &[u8]) -> bool {
+    let d = NodeData(data);
+    let _ = match kind {
+        HW_PCI if data.len() == 2 => write!(s, "Pci(0x{:X},0x{:X})", data[1], data[0]),
+        HW_MEMMAP if data.len() == 20 => write!(
+            s,
+            "MemoryMapped(0x{:X},0x{:X},0x{:X})",
+            d.u32(0),
+            d.u64(4),
+            d.u64(12)
+        ),
+        HW_VENDOR if data.len() >= 16 => {
+            write_vendor(s, "VenHw", data);
+            Ok(())
+        }
+        HW_CONTROLLER if data.len() == 4 => write!(s, "Ctrl(0x{:X})", d.u32(0)),
+        ACPI_ACPI if data.len() == 8 => {
+            let hid = d.u32(0);
+            let uid = d.u32(4);
+            if hid & 0xFFFF == PNP_EISA_ID {
+                write!(s, "Acpi(PNP{:04X},0x{:X})", hid >> 16, uid)
+            } else {
+                write!(s, "Acpi(0x{:08X},0x{:X})", hid, uid)
+            }
+        }
+        MSG_SCSI if data.len() == 4 => write!(s, "Scsi(0x{:X},0x{:X})", d.u16(0), d.u16(2)),
+        MSG_USB if data.len() == 2 => write!(s, "Usb(0x{:X},0x{:X})", data[0], data[1]),
+        MSG_VENDOR if data.len() >= 16 => {
+            write_vendor(s, "VenMsg", data);
+            Ok(())
+        }
+        MSG_MAC if data.len() == 33 && (data[32] == 0 || data[32] == 1) => {
+            s.push_str("Mac(");
+            for b in &data[..6] {
+                let _ = write!(s, "{:02x}", b);
+            }
+            write!(s, ",{:#04x})", data[32])
+        }
+        MSG_IPV4 if data.len() == 15 || data.len() == 23 => {
+            // Remote, protocol, origin, local, gateway, subnet mask
+            s.push_str("IPv4(");
+            write_ipv4(s, &data[4..8]);
+            s.push(',');
+            write_ip_protocol(s, d.u16(12));
+            s.push_str(if data[14] == 0 { ",DHCP," } else { ",Static," });
+            write_ipv4(s, &data[0..4]);
+            if data.len() == 23 {
+                s.push(',');
+                write_ipv4(s, &data[15..19]);
+                s.push(',');
+                write_ipv4(s, &data[19..23]);
+            }
+            write!(s, ")")
+        }
+        MSG_IPV6 if data.len() == 56 => {
+            // Remote, protocol, origin, local, prefix length, gateway
+            s.push_str("IPv6(");
+            write_ipv6(s, &data[16..32]);
+            s.push(',');
+            write_ip_protocol(s, d.u16(36));
+            match IPV6_ORIGINS.get(data[38] as usize) {
+                Some(origin) => {
+                    let _ = write!(s, ",{},", origin);
+                }
+                None => {
+                    let _ = write!(s, ",0x{:X},", data[38]);
+                }
+            }
+            write_ipv6(s, &data[0..16]);
+            let _ = write!(s, ",0x{:X},", data[39]);
+            write_ipv6(s, &data[40..56]);
+            write!(s, ")")
+        }
+        MSG_SATA if data.len() == 6 => {
+            let hba_port = d.u16(0);
+            let multiplier_port = d.u16(2);
+            let logical_unit = d.u16(4);
+            if multiplier_port & (1 << 15) != 0 {
+                write!(s, "Sata(0x{hba_port:X},0x{logical_unit:X})")
+            } else {
+                write!(
+                    s,
+                    "Sata(0x{hba_port:X},0x{multiplier_port:X},0x{logical_unit:X})"
+                )
+            }
+        }
+        MSG_NVME if data.len() == 12 => {
+            let nsid = d.u32(0);
+            let eui = &data[4..];
+            if eui == [0; 8] {
+                write!(s, "NVMe(0x{nsid:X})")
+            } else {
+                let _ = write!(s, "NVMe(0x{nsid:X},");
+                for (i, b) in eui.iter().enumerate() {
+                    if i > 0 {
+                        s.push('-');
+                    }
+                    let _ = write!(s, "{:02X}", b);
+                }
+                write!(s, ")")
+            }
+        }
+        MSG_URI if data.iter().all(|b| b.is_ascii_graphic()) => {
+            s.push_str("Uri(");
+            s.extend(data.iter().map(|&b| b as char));
+            write!(s, ")")
+        }
+        MSG_SD if data.len() == 1 => write!(s, "SD(0x{:X})", data[0]),
+        MSG_EMMC if data.len() == 1 => write!(s, "eMMC(0x{:X})", data[0]),
+        MEDIA_HARDDRIVE if data.len() == 38 => {
+            let partition_number = d.u32(0);
+            let start = d.u64(4);
+            let size = d.u64(12);
+            let signature = &data[20..36];
+            let _ = write!(s, "HD(0x{:X},", partition_number);
+            match data[37] {
+                1 => {
+                    let _ = write!(s, "MBR,0x{:X}", d.u32(20));
+                }
+                2 => {
+                    s.push_str("GPT,");
+                    write_guid(s, signature);
+                }
+                signature_type => {
+                    let _ = write!(s, "0x{:X},", signature_type);
+                    write_hex(s, signature);
+                }
+            }
+            write!(s, ",0x{:X},0x{:X})", start, size)
+        }
+        MEDIA_CDROM if data.len() == 20 => write!(
+            s,
+            "CDROM(0x{:X},0x{:X},0x{:X})",
+            d.u32(0),
+            d.u64(4),
+            d.u64(12)
+        ),
+        MEDIA_VENDOR if data.len() >= 16 => {
+            write_vendor(s, "VenMedia", data);
+            Ok(())
+        }
+        MEDIA_FILEPATH => {
+            for chunk in data.chunks_exact(2) {
+                match char::from_u32(u16::from_le_bytes([chunk[0], chunk[1]]) as u32) {
+                    Some('\0') => break,
+                    Some('\\') => s.push('/'),
+                    Some(c) => s.push(c),
+                    None => s.push(char::REPLACEMENT_CHARACTER),
+                }
+            }
+            Ok(())
+        }
+        _ => return false,
+    };
+    true
+}
+
+pub fn device_path_to_string(device_path: &DevicePath) -> String {
+    let mut s = String::new();
+    for (node, node_data) in DevicePathIter::new(device_path) {
+        if !s.is_empty() {
+            s.push('/');
+        }
+
+        if !write_node(&mut s, (node.Type, node.SubType), node_data) {
+            // Generic form for nodes without a specific text form
+            let _ = write!(s, "Path(0x{:X},0x{:X},", node.Type, node.SubType);
+            write_hex(&mut s, node_data);
+            s.push(')');
+        }
+    }
+    s
+}
+
+fn parse_int(text: &str) -> Option<u64> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Parse a GUID into the mixed endian EFI_GUID layout
+fn parse_guid(text: &str) -> Option<[u8; 16]> {
+    let mut parts = text.trim().split('-');
+    let mut guid = [0; 16];
+    let a = u32::from_str_radix(parts.next().filter(|x| x.len() == 8)?, 16).ok()?;
+    let b = u16::from_str_radix(parts.next().filter(|x| x.len() == 4)?, 16).ok()?;
+    let c = u16::from_str_radix(parts.next().filter(|x| x.len() == 4)?, 16).ok()?;
+    let d = parse_hex(parts.next().filter(|x| x.len() == 4)?)?;
+    let e = parse_hex(parts.next().filter(|x| x.len() == 12)?)?;
+    if parts.next().is_some() {
+        return None;
+    }
+    guid[0..4].copy_from_slice(&a.to_le_bytes());
+    guid[4..6].copy_from_slice(&b.to_le_bytes());
+    guid[6..8].copy_from_slice(&c.to_le_bytes());
+    guid[8..10].copy_from_slice(&d);
+    guid[10..16].copy_from_slice(&e);
+    Some(guid)
+}
+
+fn parse_ipv4(text: &str) -> Option<[u8; 4]> {
+    let mut ip = [0; 4];
+    let mut parts = text.trim().split('.');
+    for b in ip.iter_mut() {
+        *b = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(ip)
+}
+
+/// Parse an IPv6 address, which may use `::` for zero groups
+fn parse_ipv6(text: &str) -> Option<[u8; 16]> {
+    let text = text.trim();
+    let parse_groups = |text: &str| -> Option<Vec<u16>> {
+        if text.is_empty() {
+            return Some(Vec::new());
+        }
+        text.split(':')
+            .map(|group| u16::from_str_radix(group, 16).ok())
+            .collect()
+    };
+
+    let groups = match text.split_once("::") {
+        Some((head, tail)) => {
+            let head = parse_groups(head)?;
+            let tail = parse_groups(tail)?;
+            if head.len() + tail.len() > 7 {
+                return None;
+            }
+            let mut groups = head;
+            groups.resize(8 - tail.len(), 0);
+            groups.extend(tail);
+            groups
+        }
+        None => parse_groups(text)?,
+    };
+    if groups.len() != 8 {
+        return None;
+    }
+
+    let mut ip = [0; 16];
+    for (i, group) in groups.iter().enumerate() {
+        ip[i * 2..i * 2 + 2].copy_from_slice(&group.to_be_bytes());
+    }
+    Some(ip)
+}
+
+fn parse_ip_protocol(text: &str) -> Option<u16> {
+    match text.trim() {
+        "TCP" => Some(IP_PROTOCOL_TCP),
+        "UDP" => Some(IP_PROTOCOL_UDP),
+        other => parse_int(other)?.try_into().ok(),
+    }
+}
+
+fn parse_vendor(args: &[&str]) -> Option<Vec<u8>> {
+    let mut data = Vec::from(parse_guid(args.first()?)?);
+    match args {
+        [_] => (),
+        [_, vendor_data] => data.extend(parse_hex(vendor_data)?),
+        _ => return None,
+    }
+    Some(data)
+}
+
+/// Parse the arguments of a node in text form into its type, subtype and data
+fn parse_node(name: &str, inner: &str) -> Option<((u8, u8), Vec<u8>)> {
+    let args: Vec<&str> = inner.split(',').map(str::trim).collect();
+    let int = |i: usize| -> Option<u64> { parse_int(args.get(i)?) };
+    let mut data = Vec::new();
+
+    let kind = match (name, args.len()) {
+        ("Pci", 2) => {
+            data.push(int(1)?.try_into().ok()?);
+            data.push(int(0)?.try_into().ok()?);
+            HW_PCI
+        }
+        ("MemoryMapped", 3) => {
+            data.extend(u32::try_from(int(0)?).ok()?.to_le_bytes());
+            data.extend(int(1)?.to_le_bytes());
+            data.extend(int(2)?.to_le_bytes());
+            HW_MEMMAP
+        }
+        ("VenHw", _) => {
+            data = parse_vendor(&args)?;
+            HW_VENDOR
+        }
+        ("Ctrl", 1) => {
+            data.extend(u32::try_from(int(0)?).ok()?.to_le_bytes());
+            HW_CONTROLLER
+        }
+        ("Acpi", 2) => {
+            let hid = match args[0].strip_prefix("PNP") {
+                Some(id) => PNP_EISA_ID | u32::from_str_radix(id, 16).ok()? << 16,
+                None => int(0)?.try_into().ok()?,
+            };
+            data.extend(hid.to_le_bytes());
+            data.extend(u32::try_from(int(1)?).ok()?.to_le_bytes());
+            ACPI_ACPI
+        }
+        ("PciRoot", 1) | ("PcieRoot", 1) => {
+            let id = if name == "PciRoot" {
+                PNP_PCI_ROOT
+            } else {
+                PNP_PCIE_ROOT
+            };
+            data.extend((PNP_EISA_ID | id << 16).to_le_bytes());
+            data.extend(u32::try_from(int(0)?).ok()?.to_le_bytes());
+            ACPI_ACPI
+        }
+        ("Scsi", 2) => {
+            data.extend(u16::try_from(int(0)?).ok()?.to_le_bytes());
+            data.extend(u16::try_from(int(1)?).ok()?.to_le_bytes());
+            MSG_SCSI
+        }
+        ("Usb", 2) => {
+            data.push(int(0)?.try_into().ok()?);
+            data.push(int(1)?.try_into().ok()?);
+            MSG_USB
+        }
+        ("VenMsg", _) => {
+            data = parse_vendor(&args)?;
+            MSG_VENDOR
+        }
+        ("Mac", 2) => {
+            let mac = parse_hex(args[0])?;
+            if mac.len() > 32 {
+                return None;
+            }
+            data.extend(&mac);
+            data.resize(32, 0);
+            data.push(int(1)?.try_into().ok()?);
+            MSG_MAC
+        }
+        ("IPv4", 3..=6) => {
+            let origin = match args[2] {
+                "DHCP" => 0,
+                "Static" => 1,
+                _ => return None,
+            };
+            let ip = |i: usize| -> Option<[u8; 4]> {
+                args.get(i).map_or(Some([0; 4]), |ip| parse_ipv4(ip))
+            };
+            data.extend(ip(3)?);
+            data.extend(parse_ipv4(args[0])?);
+            // Ports are not part of the text form
+            data.extend([0; 4]);
+            data.extend(parse_ip_protocol(args[1])?.to_le_bytes());
+            data.push(origin);
+            data.extend(ip(4)?);
+            data.extend(ip(5)?);
+            MSG_IPV4
+        }
+        ("IPv6", 3..=6) => {
+            let origin = match IPV6_ORIGINS.iter().position(|origin| *origin == args[2]) {
+                Some(origin) => origin as u8,
+                None => int(2)?.try_into().ok()?,
+            };
+            let ip = |i: usize| -> Option<[u8; 16]> {
+                args.get(i).map_or(Some([0; 16]), |ip| parse_ipv6(ip))
+            };
+            data.extend(ip(3)?);
+            data.extend(parse_ipv6(args[0])?);
+            data.extend([0; 4]);
+            data.extend(parse_ip_protocol(args[1])?.to_le_bytes());
+            data.push(origin);
+            data.push(
+                args.get(4)
+                    .map_or(Some(0), |x| parse_int(x))?
+                    .try_into()
+                    .ok()?,
+            );
+            data.extend(ip(5)?);
+            MSG_IPV6
+        }
+        ("Sata", 2 | 3) => {
+            let (multiplier_port, logical_unit) = match args.len() {
+                2 => (0xFFFF, int(1)?),
+                _ => (int(1)?, int(2)?),
+            };
+            data.extend(u16::try_from(int(0)?).ok()?.to_le_bytes());
+            data.extend(u16::try_from(multiplier_port).ok()?.to_le_bytes());
+            data.extend(u16::try_from(logical_unit).ok()?.to_le_bytes());
+            MSG_SATA
+        }
+        ("NVMe", 1 | 2) => {
+            data.extend(u32::try_from(int(0)?).ok()?.to_le_bytes());
+            match args.get(1) {
+                Some(eui) => {
+                    let eui = parse_hex(&eui.replace('-', ""))?;
+                    if eui.len() != 8 {
+                        return None;
+                    }
+                    data.extend(eui);
+                }
+                None => data.extend([0; 8]),
+            }
+            MSG_NVME
+        }
+        // Commas are part of the URI
+        ("Uri", _) => {
+            data.extend(inner.trim().as_bytes());
+            MSG_URI
+        }
+        ("SD", 1) => {
+            data.push(int(0)?.try_into().ok()?);
+            MSG_SD
+        }
+        ("eMMC", 1) => {
+            data.push(int(0)?.try_into().ok()?);
+            MSG_EMMC
+        }
+        ("HD", 3 | 5) => {
+            let (signature, signature_type) = match args[1] {
+                "MBR" => {
+                    let mut signature = [0; 16];
+                    signature[..4].copy_from_slice(&u32::try_from(int(2)?).ok()?.to_le_bytes());
+                    (signature, 1)
+                }
+                "GPT" => (parse_guid(args[2])?, 2),
+                other => (
+                    parse_hex(args[2])?.try_into().ok()?,
+                    u8::try_from(parse_int(other)?).ok()?,
+                ),
+            };
+            // Start and size may be left out when matching partitions
+            let (start, size) = match args.len() {
+                5 => (int(3)?, int(4)?),
+                _ => (0, 0),
+            };
+            data.extend(u32::try_from(int(0)?).ok()?.to_le_bytes());
+            data.extend(start.to_le_bytes());
+            data.extend(size.to_le_bytes());
+            data.extend(signature);
+            // Partition format matches the signature type for MBR and GPT
+            data.push(signature_type);
+            data.push(signature_type);
+            MEDIA_HARDDRIVE
+        }
+        ("CDROM", 3) => {
+            data.extend(u32::try_from(int(0)?).ok()?.to_le_bytes());
+            data.extend(int(1)?.to_le_bytes());
+            data.extend(int(2)?.to_le_bytes());
+            MEDIA_CDROM
+        }
+        ("VenMedia", _) => {
+            data = parse_vendor(&args)?;
+            MEDIA_VENDOR
+        }
+        ("Path", 3) => {
+            data = parse_hex(args[2])?;
+            (int(0)?.try_into().ok()?, int(1)?.try_into().ok()?)
+        }
+        _ => return None,
+    };
+    Some((kind, data))
+}
+
+/// Length of a node in text form at the start of text, such as `Pci(0x1F,0x2)`
+fn node_text_len(text: &str) -> Option<usize> {
+    let name_len = text
+        .bytes()
+        .position(|b| !b.is_ascii_alphanumeric())
+        .filter(|&i| i > 0 && text.as_bytes()[i] == b'(')?;
+
+    // Parentheses may be nested, for example in a URI
+    let mut depth = 0;
+    for (i, b) in text.bytes().enumerate().skip(name_len) {
+        match b {
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn push_node(device_path: &mut Vec<u8>, kind: (u8, u8), data: &[u8]) -> Option<()> {
+    let length = u16::try_from(4 + data.len()).ok()?;
+    device_path.extend([kind.0, kind.1]);
+    device_path.extend(length.to_le_bytes());
+    device_path.extend(data);
+    Some(())
+}
+
+/// Parse the text form of a device path, as returned by [device_path_to_string], into the
+/// binary form including the end node
+///
+/// Text that is not a node, such as `/EFI/BOOT/BOOTX64.EFI`, is a file path.
+pub fn device_path_from_str(text: &str) -> Option<Vec<u8>> {
+    let mut device_path = Vec::new();
+
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        if let Some(len) = node_text_len(rest) {
+            let open = rest.find('(')?;
+            let (kind, data) = parse_node(&rest[..open], &rest[open + 1..len - 1])?;
+            push_node(&mut device_path, kind, &data)?;
+
+            rest = &rest[len..];
+            if let Some(next) = rest.strip_prefix('/') {
+                rest = next;
+            } else if !rest.is_empty() {
+                return None;
+            }
+        } else {
+            // The file path continues until the next node
+            let mut end = rest.len();
+            for (i, _) in rest.match_indices('/') {
+                if i > 0 && node_text_len(&rest[i + 1..]).is_some() {
+                    end = i;
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            for c in rest[..end].encode_utf16().chain([0]) {
+                let c = if c == b'/' as u16 { b'\\' as u16 } else { c };
+                data.extend(c.to_le_bytes());
+            }
+            push_node(&mut device_path, MEDIA_FILEPATH, &data)?;
+
+            rest = rest[end..].strip_prefix('/').unwrap_or("");
+        }
+    }
+
+    push_node(
+        &mut device_path,
+        (DevicePathType::End as u8, DevicePathEndType::Entire as u8),
+        &[],
+    )?;
+    Some(device_path)
+}
+
+/// Parse a device pattern into a device path if it consists only of nodes. Other text, such as
+/// `NVMe`, parses as a file path and is matched against the text form instead.
+pub fn device_path_pattern(text: &str) -> Option<Vec<u8>> {
+    let device_path = device_path_from_str(text)?;
+    let nodes = DevicePathIter::new(unsafe { &*(device_path.as_ptr() as *const DevicePath) });
+    for (node, _) in nodes {
+        if (node.Type, node.SubType) == MEDIA_FILEPATH {
+            return None;
+        }
+    }
+    Some(device_path)
+}
+
+fn node_matches(a: &(DevicePath, &[u8]), b: &(DevicePath, &[u8])) -> bool {
+    let kind = (a.0.Type, a.0.SubType);
+    if kind != (b.0.Type, b.0.SubType) {
+        return false;
+    }
+
+    if kind == MEDIA_HARDDRIVE && a.1.len() == 38 && b.1.len() == 38 {
+        // Compare partition number and signature, skipping start and size
+        return a.1[..4] == b.1[..4] && a.1[20..] == b.1[20..];
+    }
+
+    a.1 == b.1
+}
+
+/// Check if the nodes of pattern appear in order in device_path, so that for example
+/// `PciRoot(0x0)` matches `Acpi(PNP0A03,0x0)`. Hard drive nodes match regardless of start and
+/// size, which may be left out as in `HD(2,GPT,<guid>)`.
+pub fn device_path_contains(device_path: &DevicePath, pattern: &DevicePath) -> bool {
+    let nodes: Vec<_> = DevicePathIter::new(device_path).collect();
+    let pattern: Vec<_> = DevicePathIter::new(pattern).collect();
+    !pattern.is_empty()
+        && nodes.windows(pattern.len()).any(|window| {
+            window
+                .iter()
+                .zip(&pattern)
+                .all(|(node, pattern_node)| node_matches(node, pattern_node))
+        })
+}
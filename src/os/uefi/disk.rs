@@ -1,27 +1,34 @@
-use alloc::vec::Vec;
-use core::slice;
+use alloc::boxed::Box;
+use core::{cmp, ptr, slice};
 use redoxfs::{BLOCK_SIZE, Disk, RECORD_SIZE};
 use std::proto::Protocol;
 use syscall::{EINVAL, EIO, Error, Result};
-use uefi::block_io::BlockIo as UefiBlockIo;
+use uefi::block_io::{BlockIo as UefiBlockIo, BlockIoMedia};
 use uefi::guid::{BLOCK_IO_GUID, Guid};
+use uefi::status::Status;
+use uefi::{Event, Handle, Tpl};
+
+use crate::disk_cache::{CachedDisk, DiskCache, DiskCacheStats};
 
 pub enum DiskOrFileEfi {
-    Disk(DiskEfi),
-    File(Vec<u8>),
+    Disk(DiskCache<DiskEfi>),
+    File(&'static [u8]),
 }
 
 impl redoxfs::Disk for DiskOrFileEfi {
     unsafe fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> syscall::Result<usize> {
         unsafe {
             match self {
-                DiskOrFileEfi::Disk(disk_efi) => disk_efi.read_at(block, buffer),
+                DiskOrFileEfi::Disk(disk) => disk.read_at(block, buffer),
                 DiskOrFileEfi::File(data) => {
-                    buffer.copy_from_slice(
-                        &data[(block * redoxfs::BLOCK_SIZE) as usize
-                            ..(block * redoxfs::BLOCK_SIZE) as usize + buffer.len()],
-                    );
-                    Ok(buffer.len())
+                    let start = (block * redoxfs::BLOCK_SIZE) as usize;
+                    match data.get(start..start + buffer.len()) {
+                        Some(slice) => {
+                            buffer.copy_from_slice(slice);
+                            Ok(buffer.len())
+                        }
+                        None => Err(Error::new(EIO)),
+                    }
                 }
             }
         }
@@ -32,11 +39,210 @@ impl redoxfs::Disk for DiskOrFileEfi {
     }
 
     fn size(&mut self) -> syscall::Result<u64> {
-        unreachable!()
+        match self {
+            DiskOrFileEfi::Disk(disk) => disk.size(),
+            DiskOrFileEfi::File(data) => Ok(data.len() as u64),
+        }
+    }
+}
+
+impl CachedDisk for DiskOrFileEfi {
+    fn cache_stats(&self) -> Option<DiskCacheStats> {
+        match self {
+            DiskOrFileEfi::Disk(disk) => Some(disk.stats()),
+            DiskOrFileEfi::File(_) => None,
+        }
+    }
+}
+
+const BLOCK_IO2_GUID: Guid = Guid::parse_str("a77b2472-e282-4e9f-a245-c2c0e27bbcc1");
+const DISK_IO_GUID: Guid = Guid::parse_str("ce345171-ba0b-11d2-8e4f-00a0c969723b");
+
+/// Number of EFI_BLOCK_IO2 reads in flight
+const QUEUE_DEPTH: usize = 8;
+/// Size of each EFI_BLOCK_IO2 read, rounded down to device blocks
+const QUEUE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct BlockIo2Token {
+    pub Event: Event,
+    pub TransactionStatus: Status,
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct BlockIo2Protocol {
+    pub Media: &'static BlockIoMedia,
+    pub Reset: extern "efiapi" fn(&BlockIo2Protocol, ExtendedVerification: bool) -> Status,
+    pub ReadBlocksEx: extern "efiapi" fn(
+        &BlockIo2Protocol,
+        MediaId: u32,
+        LBA: u64,
+        Token: *mut BlockIo2Token,
+        BufferSize: usize,
+        Buffer: *mut u8,
+    ) -> Status,
+    pub WriteBlocksEx: usize,
+    pub FlushBlocksEx: usize,
+}
+
+pub struct BlockIo2(pub &'static mut BlockIo2Protocol);
+
+impl Protocol<BlockIo2Protocol> for BlockIo2 {
+    fn guid() -> Guid {
+        BLOCK_IO2_GUID
+    }
+
+    fn new(inner: &'static mut BlockIo2Protocol) -> Self {
+        BlockIo2(inner)
+    }
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct DiskIoProtocol {
+    pub Revision: u64,
+    pub ReadDisk: extern "efiapi" fn(
+        &DiskIoProtocol,
+        MediaId: u32,
+        Offset: u64,
+        BufferSize: usize,
+        Buffer: *mut u8,
+    ) -> Status,
+    pub WriteDisk: usize,
+}
+
+pub struct DiskIo(pub &'static mut DiskIoProtocol);
+
+impl Protocol<DiskIoProtocol> for DiskIo {
+    fn guid() -> Guid {
+        DISK_IO_GUID
+    }
+
+    fn new(inner: &'static mut DiskIoProtocol) -> Self {
+        DiskIo(inner)
+    }
+}
+
+extern "efiapi" fn event_notify(_event: Event, _context: usize) {}
+
+/// EFI_BLOCK_IO2 with events for queued reads
+struct BlockIo2Queue {
+    block_io2: BlockIo2,
+    events: [Event; QUEUE_DEPTH],
+}
+
+impl BlockIo2Queue {
+    fn new(block_io2: BlockIo2) -> Option<Self> {
+        // Events created before a failure are closed when this is dropped
+        let mut queue = Self {
+            block_io2,
+            events: [Event(0); QUEUE_DEPTH],
+        };
+        for event in queue.events.iter_mut() {
+            // No notification, the event is only waited on
+            let status =
+                (std::system_table().BootServices.CreateEvent)(0, Tpl(0), event_notify, 0, event);
+            if !status.is_success() {
+                log::warn!("Failed to create block I/O 2 event: {:?}", status);
+                return None;
+            }
+        }
+        Some(queue)
+    }
+
+    /// Wait for a queued read, returning its status
+    fn wait(&self, token: &BlockIo2Token) -> Status {
+        let mut index = 0;
+        let status = (std::system_table().BootServices.WaitForEvent)(1, &token.Event, &mut index);
+        if status.is_success() {
+            unsafe { ptr::read_volatile(&token.TransactionStatus) }
+        } else {
+            status
+        }
+    }
+
+    /// Read whole device blocks with several reads in flight
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<()> {
+        let protocol = &*self.block_io2.0;
+        let block_size = protocol.Media.BlockSize as usize;
+        let chunk_size = cmp::max(QUEUE_CHUNK_SIZE / block_size, 1) * block_size;
+
+        let mut tokens: [BlockIo2Token; QUEUE_DEPTH] = core::array::from_fn(|i| BlockIo2Token {
+            Event: self.events[i],
+            TransactionStatus: Status::SUCCESS,
+        });
+        let mut in_flight = [false; QUEUE_DEPTH];
+        let mut res = Ok(());
+
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let slot = i % QUEUE_DEPTH;
+            if in_flight[slot] {
+                in_flight[slot] = false;
+                let status = self.wait(&tokens[slot]);
+                if !status.is_success() {
+                    println!("DiskEfi::read_blocks 0x{:X} failed: {:?}", lba, status);
+                    res = Err(Error::new(EIO));
+                    break;
+                }
+            }
+
+            let chunk_lba = lba + (i * chunk_size / block_size) as u64;
+            let status = (protocol.ReadBlocksEx)(
+                protocol,
+                protocol.Media.MediaId,
+                chunk_lba,
+                &mut tokens[slot],
+                chunk.len(),
+                chunk.as_mut_ptr(),
+            );
+            if !status.is_success() {
+                println!(
+                    "DiskEfi::read_blocks 0x{:X} failed: {:?}",
+                    chunk_lba, status
+                );
+                res = Err(Error::new(EIO));
+                break;
+            }
+            in_flight[slot] = true;
+        }
+
+        // The buffer must not be released while reads are still writing to it
+        for slot in 0..QUEUE_DEPTH {
+            if in_flight[slot] {
+                let status = self.wait(&tokens[slot]);
+                if !status.is_success() && res.is_ok() {
+                    println!("DiskEfi::read_blocks 0x{:X} failed: {:?}", lba, status);
+                    res = Err(Error::new(EIO));
+                }
+            }
+        }
+
+        res
     }
 }
 
-pub struct DiskEfi(pub &'static mut UefiBlockIo, &'static mut [u8]);
+impl Drop for BlockIo2Queue {
+    fn drop(&mut self) {
+        for event in self.events.iter() {
+            if *event != Event(0) {
+                let _ = (super::boot_services_full().CloseEvent)(*event);
+            }
+        }
+    }
+}
+
+/// Block device, with a bounce buffer aligned to IoAlign for reads that are not aligned to the
+/// device blocks or to IoAlign
+///
+/// EFI_BLOCK_IO2 is used for large reads and EFI_DISK_IO for unaligned reads, if available.
+pub struct DiskEfi {
+    pub block_io: &'static mut UefiBlockIo,
+    bounce: &'static mut [u8],
+    block_io2_opt: Option<Box<BlockIo2Queue>>,
+    disk_io_opt: Option<DiskIo>,
+}
 
 impl Protocol<UefiBlockIo> for DiskEfi {
     fn guid() -> Guid {
@@ -44,67 +250,182 @@ impl Protocol<UefiBlockIo> for DiskEfi {
     }
 
     fn new(inner: &'static mut UefiBlockIo) -> Self {
-        // Hack to get aligned buffer
-        let block = unsafe {
-            let ptr = super::alloc_zeroed_page_aligned(RECORD_SIZE as usize);
-            slice::from_raw_parts_mut(ptr, RECORD_SIZE as usize)
+        let block_size = cmp::max(inner.Media.BlockSize as usize, 1);
+        let io_align = cmp::max(inner.Media.IoAlign as usize, 1);
+
+        // Hold at least one device block, which may be larger than a RedoxFS record
+        let size = (RECORD_SIZE as usize).div_ceil(block_size) * block_size;
+        let bounce = unsafe {
+            // Pages are already aligned for all but very large IoAlign values
+            let extra = if io_align > super::page_size() {
+                io_align
+            } else {
+                0
+            };
+            let ptr = super::alloc_zeroed_page_aligned(size + extra);
+            let ptr = ptr.add(ptr.align_offset(io_align));
+            slice::from_raw_parts_mut(ptr, size)
         };
 
-        Self(inner, block)
+        Self {
+            block_io: inner,
+            bounce,
+            block_io2_opt: None,
+            disk_io_opt: None,
+        }
     }
 }
 
-impl Disk for DiskEfi {
-    unsafe fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        unsafe {
-            // Optimization for live disks
-            if let Some(live) = crate::LIVE_OPT {
-                if block >= live.0 {
-                    let start = ((block - live.0) * BLOCK_SIZE) as usize;
-                    let end = start + buffer.len();
-                    if end <= live.1.len() {
-                        buffer.copy_from_slice(&live.1[start..end]);
-                        return Ok(buffer.len());
-                    }
-                }
+fn read_blocks(block_io: &UefiBlockIo, lba: u64, buffer: &mut [u8]) -> Result<()> {
+    match (block_io.ReadBlocks)(
+        block_io,
+        block_io.Media.MediaId,
+        lba,
+        buffer.len(),
+        buffer.as_mut_ptr(),
+    ) {
+        status if status.is_success() => Ok(()),
+        err => {
+            println!(
+                "DiskEfi::read_blocks 0x{:X} len 0x{:X} failed: {:?}",
+                lba,
+                buffer.len(),
+                err
+            );
+            Err(Error::new(EIO))
+        }
+    }
+}
+
+fn read_disk(disk_io: &DiskIo, media_id: u32, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    match (disk_io.0.ReadDisk)(
+        disk_io.0,
+        media_id,
+        offset,
+        buffer.len(),
+        buffer.as_mut_ptr(),
+    ) {
+        status if status.is_success() => Ok(()),
+        err => {
+            println!(
+                "DiskEfi::read_disk 0x{:X} len 0x{:X} failed: {:?}",
+                offset,
+                buffer.len(),
+                err
+            );
+            Err(Error::new(EIO))
+        }
+    }
+}
+
+impl DiskEfi {
+    /// Also use EFI_BLOCK_IO2 and EFI_DISK_IO if the handle provides them
+    pub fn with_handle(mut self, handle: Handle) -> Self {
+        // Media must match, or the protocols may refer to a different device
+        let media_id = self.block_io.Media.MediaId;
+        let block_size = self.block_io.Media.BlockSize;
+
+        if let Ok(block_io2) = BlockIo2::handle_protocol(handle) {
+            if block_io2.0.Media.MediaId == media_id && block_io2.0.Media.BlockSize == block_size {
+                self.block_io2_opt = BlockIo2Queue::new(block_io2).map(Box::new);
             }
+        }
+        if let Ok(disk_io) = DiskIo::handle_protocol(handle) {
+            self.disk_io_opt = Some(disk_io);
+        }
+
+        log::debug!(
+            "DiskEfi {:?}: block I/O 2 {}, disk I/O {}",
+            handle,
+            self.block_io2_opt.is_some(),
+            self.disk_io_opt.is_some()
+        );
+        self
+    }
 
-            // Use aligned buffer if necessary
-            let mut ptr = buffer.as_mut_ptr();
-            if self.0.Media.IoAlign != 0 {
-                if (ptr as usize) % (self.0.Media.IoAlign as usize) != 0 {
-                    if buffer.len() <= self.1.len() {
-                        ptr = self.1.as_mut_ptr();
-                    } else {
-                        println!(
-                            "DiskEfi::read_at 0x{:X} requires alignment, ptr = 0x{:p}, len = 0x{:x}",
-                            block,
-                            ptr,
-                            buffer.len()
-                        );
-                        return Err(Error::new(EINVAL));
+    /// Read at a byte offset, going through the bounce buffer for parts that cannot be read
+    /// directly into the buffer
+    fn read_bytes(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let block_size = self.block_io.Media.BlockSize as u64;
+        let io_align = cmp::max(self.block_io.Media.IoAlign as usize, 1);
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let pos = offset + done as u64;
+            let lba = pos / block_size;
+            let skip = (pos % block_size) as usize;
+            let remaining = &mut buffer[done..];
+
+            // Read whole device blocks directly if the buffer is suitably aligned
+            let direct = (remaining.len() / block_size as usize) * block_size as usize;
+            if skip == 0 && direct > 0 && (remaining.as_ptr() as usize) % io_align == 0 {
+                match &self.block_io2_opt {
+                    Some(queue) if direct > QUEUE_CHUNK_SIZE => {
+                        queue.read_blocks(lba, &mut remaining[..direct])?
                     }
+                    _ => read_blocks(self.block_io, lba, &mut remaining[..direct])?,
                 }
+                done += direct;
+                continue;
             }
 
-            let block_size = self.0.Media.BlockSize as u64;
-            let lba = block * BLOCK_SIZE / block_size;
+            // Disk I/O handles alignment itself
+            if let Some(disk_io) = &self.disk_io_opt {
+                read_disk(disk_io, self.block_io.Media.MediaId, pos, remaining)?;
+                done = buffer.len();
+                continue;
+            }
 
-            match (self.0.ReadBlocks)(self.0, self.0.Media.MediaId, lba, buffer.len(), ptr) {
-                status if status.is_success() => {
-                    // Copy to original buffer if using aligned buffer
-                    if ptr != buffer.as_mut_ptr() {
-                        let (left, _) = self.1.split_at(buffer.len());
-                        buffer.copy_from_slice(left);
-                    }
-                    Ok(buffer.len())
-                }
-                err => {
-                    println!("DiskEfi::read_at 0x{:X} failed: {:?}", block, err);
-                    Err(Error::new(EIO))
+            let bounce_len = cmp::min(
+                (skip + remaining.len()).div_ceil(block_size as usize) * block_size as usize,
+                self.bounce.len(),
+            );
+            let count = cmp::min(bounce_len - skip, remaining.len());
+            let bounce = &mut self.bounce[..bounce_len];
+            read_blocks(self.block_io, lba, bounce)?;
+            remaining[..count].copy_from_slice(&bounce[skip..skip + count]);
+            done += count;
+        }
+
+        Ok(())
+    }
+}
+
+impl Disk for DiskEfi {
+    unsafe fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        // Optimization for live disks
+        if let Some(live) = unsafe { crate::LIVE_OPT } {
+            if block >= live.0 {
+                let start = ((block - live.0) * BLOCK_SIZE) as usize;
+                let end = start + buffer.len();
+                if end <= live.1.len() {
+                    buffer.copy_from_slice(&live.1[start..end]);
+                    return Ok(buffer.len());
                 }
             }
         }
+
+        let size = self.size()?;
+        let end = block
+            .checked_mul(BLOCK_SIZE)
+            .and_then(|start| start.checked_add(buffer.len() as u64));
+        if end.is_none_or(|end| end > size) {
+            println!(
+                "DiskEfi::read_at 0x{:X} len 0x{:X} past end of disk 0x{:X}",
+                block,
+                buffer.len(),
+                size
+            );
+            return Err(Error::new(EIO));
+        }
+
+        if self.block_io.Media.BlockSize == 0 {
+            println!("DiskEfi::read_at 0x{:X} invalid block size", block);
+            return Err(Error::new(EINVAL));
+        }
+
+        self.read_bytes(block * BLOCK_SIZE, buffer)?;
+        Ok(buffer.len())
     }
 
     unsafe fn write_at(&mut self, block: u64, _buffer: &[u8]) -> Result<usize> {
@@ -113,7 +434,7 @@ impl Disk for DiskEfi {
     }
 
     fn size(&mut self) -> Result<u64> {
-        println!("DiskEfi::size not implemented");
-        Err(Error::new(EIO))
+        // LastBlock is the index of the last block, not the count
+        Ok((self.block_io.Media.LastBlock + 1) * self.block_io.Media.BlockSize as u64)
     }
 }
@@ -0,0 +1,89 @@
+use alloc::format;
+use core::{ptr, slice};
+use uefi::{Handle, boot::LocateSearchType, status::Result};
+use uefi_std::{ffi::wstr, fs::FileSystem, proto::Protocol};
+
+use super::{
+    boot_services_full,
+    chainload::{read_dir, start_image},
+    device::esp_device,
+    status_to_result,
+};
+
+/// Directory on the ESP with drivers to load before looking for disks
+const DRIVERS_DIR: &str = "redox/drivers";
+
+/// Load and start every `.efi` driver in the drivers directory, returning the number started
+pub fn load_esp_drivers() -> usize {
+    let Some((esp_handle, _esp_device_path)) = esp_device() else {
+        return 0;
+    };
+    let Ok(mut esp_fs) = FileSystem::handle_protocol(esp_handle) else {
+        return 0;
+    };
+    let Ok(mut root) = esp_fs.root() else {
+        return 0;
+    };
+    let Ok(mut redox_dir) = root.open_dir(&wstr("redox")) else {
+        return 0;
+    };
+    let Ok(mut drivers_dir) = redox_dir.open_dir(&wstr("drivers")) else {
+        return 0;
+    };
+
+    let mut files = read_dir(&mut drivers_dir);
+    files.sort();
+
+    let mut count = 0;
+    for (file, is_dir) in files {
+        if is_dir || !file.to_ascii_lowercase().ends_with(".efi") {
+            continue;
+        }
+
+        let path = format!("{}/{}", DRIVERS_DIR, file);
+        match start_image(&path) {
+            Ok(()) => count += 1,
+            Err(err) => log::warn!("Failed to load driver {}: {:?}", path, err),
+        }
+    }
+    count
+}
+
+fn all_handles() -> Result<&'static [Handle]> {
+    let mut count = 0;
+    let mut buffer = ptr::null_mut();
+    status_to_result((std::system_table().BootServices.LocateHandleBuffer)(
+        LocateSearchType::AllHandles,
+        ptr::null(),
+        ptr::null(),
+        &mut count,
+        &mut buffer,
+    ))?;
+    Ok(unsafe { slice::from_raw_parts(buffer, count) })
+}
+
+/// Connect drivers to all controllers, so that devices the firmware did not need for booting
+/// this program are available
+pub fn connect_all_controllers() {
+    let boot_services = &std::system_table().BootServices;
+    let connect_controller = boot_services_full().ConnectController;
+
+    let handles = match all_handles() {
+        Ok(ok) => ok,
+        Err(err) => {
+            log::warn!("Failed to find handles: {:?}", err);
+            return;
+        }
+    };
+
+    let mut connected = 0;
+    for &handle in handles {
+        // Most handles are not controllers, so errors are expected
+        if (connect_controller)(handle, ptr::null(), 0, true).is_success() {
+            connected += 1;
+        }
+    }
+    log::debug!("Connected {} of {} handles", connected, handles.len());
+
+    let _ = (boot_services.FreePool)(handles.as_ptr() as usize);
+}
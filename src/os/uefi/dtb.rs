@@ -94,6 +94,23 @@ fn find_smbios3_system(address: *const u8) -> Result<dmidecode::System<'static>>
     Err(Status::NOT_FOUND)
 }
 
+/// Page table levels supported by all harts, from `mmu-type` properties such as `riscv,sv48`
+#[cfg(target_arch = "riscv64")]
+pub fn dtb_mmu_levels(address: u64) -> Option<usize> {
+    let fdt = unsafe { Fdt::from_ptr(address as *const u8) }.ok()?;
+    let mut levels_opt: Option<usize> = None;
+    for cpu in fdt.cpus() {
+        let levels = match cpu.property("mmu-type")?.as_str()? {
+            "riscv,sv39" => 3,
+            "riscv,sv48" => 4,
+            "riscv,sv57" => 5,
+            _ => return None,
+        };
+        levels_opt = Some(levels_opt.map_or(levels, |min| min.min(levels)));
+    }
+    levels_opt
+}
+
 pub(crate) fn find_dtb(os: &impl Os) -> Option<(u64, u64)> {
     let cfg_tables = std::system_table().config_tables();
     for cfg_table in cfg_tables.iter() {
@@ -126,6 +126,34 @@ impl Iterator for MemoryMapIter {
     }
 }
 
+/// Regions that the firmware maps as write-back cacheable, sorted and merged
+#[cfg(target_arch = "aarch64")]
+pub fn cacheable_regions() -> Vec<(u64, u64)> {
+    // EFI_MEMORY_WB
+    const MEMORY_WB: u64 = 0x8;
+
+    let iter = MemoryMapIter::new();
+    let mut regions: Vec<(u64, u64)> = Vec::new();
+    for i in 0..iter.map.len() / iter.descriptor_size {
+        let descriptor_ptr = unsafe { iter.map.as_ptr().add(i * iter.descriptor_size) };
+        let descriptor = unsafe { ptr::read(descriptor_ptr as *const MemoryDescriptor) };
+        if descriptor.Attribute & MEMORY_WB != 0 {
+            regions.push((descriptor.PhysicalStart.0, descriptor.NumberOfPages * 4096));
+        }
+    }
+
+    regions.sort_unstable();
+    regions.dedup_by(|next, prev| {
+        if next.0 <= prev.0 + prev.1 {
+            prev.1 = prev.1.max(next.0 + next.1 - prev.0);
+            true
+        } else {
+            false
+        }
+    });
+    regions
+}
+
 pub unsafe fn memory_map() -> MemoryMapIter {
     let mut iter = MemoryMapIter::new();
 
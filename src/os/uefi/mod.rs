@@ -1,37 +1,54 @@
 use alloc::vec::Vec;
-use core::{cell::RefCell, mem, ptr, slice};
+use core::{
+    cell::{Cell, RefCell},
+    mem, ptr, slice,
+};
+use redoxfs::Disk;
 use std::proto::Protocol;
 use uefi::{
-    Handle,
-    boot::LocateSearchType,
+    Event, Handle, TableHeader, Tpl,
+    boot::{BootServices, LocateSearchType},
     memory::MemoryType,
     reset::ResetType,
     status::{Result, Status},
     system::SystemTable,
     text::TextInputKey,
+    time::Time,
 };
 
-use crate::os::{Os, OsHwDesc, OsKey, OsVideoMode};
+use crate::config::DeviceMatch;
+use crate::edid;
+use crate::os::{Os, OsBootEntry, OsHwDesc, OsKey, OsVideoMode};
 
 use self::{
-    device::{device_path_to_string, disk_device_priority},
+    device::{disk_device_priority, esp_boot_file},
+    device_path::device_path_to_string,
     disk::DiskOrFileEfi,
     display::{EdidActive, Output},
+    memory_map::MemoryMapIter,
     video_mode::VideoModeIter,
 };
 
 mod acpi;
 mod arch;
+mod chainload;
 mod device;
+mod device_path;
 mod disk;
 mod display;
+mod driver;
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 pub mod dtb;
 mod memory_map;
+mod pxe;
 mod video_mode;
 
 #[cfg(target_arch = "riscv64")]
 pub use arch::efi_get_boot_hartid;
+#[cfg(target_arch = "x86_64")]
+pub use arch::la57_entry_below_4g;
+#[cfg(target_arch = "aarch64")]
+pub use memory_map::cacheable_regions;
 
 pub(crate) fn page_size() -> usize {
     // EDK2 always uses 4096 as the page size
@@ -45,8 +62,8 @@ pub(crate) fn alloc_zeroed_page_aligned(size: usize) -> *mut u8 {
     let pages = size.div_ceil(page_size);
 
     let ptr = {
-        // Max address mapped by src/arch paging code (8 GiB)
-        let mut ptr = 0x2_0000_0000;
+        // Max address that src/arch paging code can map
+        let mut ptr = (crate::arch::PHYS_MAP_LIMIT - 1) as usize;
         status_to_result((std::system_table().BootServices.AllocatePages)(
             1,                                  // AllocateMaxAddress
             MemoryType::EfiRuntimeServicesData, // Keeps this memory out of free space list
@@ -65,6 +82,8 @@ pub(crate) fn alloc_zeroed_page_aligned(size: usize) -> *mut u8 {
 pub struct OsEfi {
     st: &'static SystemTable,
     outputs: RefCell<Vec<(Output, Option<EdidActive>)>>,
+    /// RedoxFS was found in a live image that is only in memory
+    live_only: Cell<bool>,
 }
 
 impl OsEfi {
@@ -145,10 +164,30 @@ impl OsEfi {
         Self {
             st,
             outputs: RefCell::new(outputs),
+            live_only: Cell::new(false),
         }
     }
 }
 
+fn os_key(key: TextInputKey) -> OsKey {
+    match key.ScanCode {
+        0 => match key.UnicodeChar {
+            8 => OsKey::Backspace,
+            13 => OsKey::Enter,
+            w => match char::from_u32(w as u32) {
+                Some(c) => OsKey::Char(c),
+                None => OsKey::Other,
+            },
+        },
+        1 => OsKey::Up,
+        2 => OsKey::Down,
+        3 => OsKey::Right,
+        4 => OsKey::Left,
+        8 => OsKey::Delete,
+        _ => OsKey::Other,
+    }
+}
+
 impl Os for OsEfi {
     type D = DiskOrFileEfi;
     type V = VideoModeIter;
@@ -176,29 +215,64 @@ impl Os for OsEfi {
         page_size()
     }
 
+    fn memory_end(&self) -> u64 {
+        MemoryMapIter::new()
+            .map(|entry| entry.base + entry.size)
+            .max()
+            .unwrap_or(0)
+    }
+
     fn filesystem(
         &self,
+        devices: &[DeviceMatch],
         password_opt: Option<&[u8]>,
     ) -> syscall::Result<redoxfs::FileSystem<DiskOrFileEfi>> {
         let mut seen_enokey = false;
+        let mut fallback_opt = None;
+        let match_uuid = devices
+            .iter()
+            .any(|device| matches!(device, DeviceMatch::Uuid(_)));
 
         // Search for RedoxFS on disks in prioritized order
         println!("Looking for RedoxFS:");
-        for device in disk_device_priority() {
+        for mut device in disk_device_priority(self, devices) {
+            let size = device
+                .disk
+                .size()
+                .map_or(0, |size| size / crate::MIBI as u64);
             if let Some(file_path) = device.file_path {
                 log::debug!(
-                    " - {}\\{}",
+                    " - {}\\{}: {} MiB",
                     device_path_to_string(device.device_path.0),
-                    file_path
+                    file_path,
+                    size
                 );
             } else {
-                log::debug!(" - {}", device_path_to_string(device.device_path.0));
+                log::debug!(
+                    " - {}: {} MiB",
+                    device_path_to_string(device.device_path.0),
+                    size
+                );
             }
 
             let block = device.partition_offset / redoxfs::BLOCK_SIZE;
 
             match redoxfs::FileSystem::open(device.disk, password_opt, Some(block), false) {
-                Ok(ok) => return Ok(ok),
+                Ok(ok) => {
+                    // Keep looking for a configured UUID, using the first RedoxFS if none match
+                    if !match_uuid
+                        || devices
+                            .iter()
+                            .any(|device| device.matches_uuid(&ok.header.uuid()))
+                    {
+                        self.live_only
+                            .set(matches!(ok.disk, DiskOrFileEfi::File(_)));
+                        return Ok(ok);
+                    }
+                    if fallback_opt.is_none() {
+                        fallback_opt = Some(ok);
+                    }
+                }
                 Err(err) => match err.errno {
                     // Ignore header not found error
                     syscall::ENOENT => (),
@@ -212,15 +286,30 @@ impl Os for OsEfi {
             }
         }
 
-        // Let the caller prompt for a password
+        // Let the caller prompt for a password, the configured UUID may be encrypted
         if seen_enokey {
             return Err(syscall::Error::new(syscall::ENOKEY));
         }
 
+        if let Some(fallback) = fallback_opt {
+            log::warn!("No RedoxFS with a configured UUID found");
+            self.live_only
+                .set(matches!(fallback.disk, DiskOrFileEfi::File(_)));
+            return Ok(fallback);
+        }
+
         log::warn!("No RedoxFS partitions found");
         Err(syscall::Error::new(syscall::ENOENT))
     }
 
+    fn live_only(&self) -> bool {
+        self.live_only.get()
+    }
+
+    fn boot_file(&self, path: &str) -> Option<&'static mut [u8]> {
+        esp_boot_file(self, path)
+    }
+
     fn hwdesc(&self) -> OsHwDesc {
         //TODO: if both DTB and ACPI are found, we should probably let the OS choose what to use?
 
@@ -237,6 +326,36 @@ impl Os for OsEfi {
         OsHwDesc::NotFound
     }
 
+    fn connect_devices(&self, connect_all: bool) {
+        // Drivers only bind to devices when controllers are connected
+        if driver::load_esp_drivers() > 0 || connect_all {
+            driver::connect_all_controllers();
+        }
+    }
+
+    fn boot_entries(&self) -> Vec<OsBootEntry> {
+        chainload::esp_boot_entries()
+    }
+
+    fn chainload(&self, entry: &OsBootEntry) {
+        if let Err(err) = chainload::start_image(&entry.path) {
+            log::error!("Failed to start {}: {:?}", entry.name, err);
+        }
+    }
+
+    fn time_ms(&self) -> Option<u64> {
+        let mut time = Time::default();
+        status_to_result((self.st.RuntimeServices.GetTime)(
+            &mut time,
+            ptr::null_mut(),
+        ))
+        .ok()?;
+
+        // Durations crossing midnight are not handled
+        let seconds = (time.Hour as u64 * 60 + time.Minute as u64) * 60 + time.Second as u64;
+        Some(seconds * 1000 + time.Nanosecond as u64 / 1_000_000)
+    }
+
     fn video_outputs(&self) -> usize {
         self.outputs.borrow().len()
     }
@@ -262,34 +381,39 @@ impl Os for OsEfi {
         // Update with actual mode information
         mode.width = output.0.Mode.Info.HorizontalResolution;
         mode.height = output.0.Mode.Info.VerticalResolution;
+        mode.stride = output.0.Mode.Info.PixelsPerScanLine;
         mode.base = output.0.Mode.FrameBufferBase as u64;
     }
 
     fn best_resolution(&self, output_i: usize) -> Option<(u32, u32)> {
-        let mut outputs = self.outputs.borrow_mut();
-        let (output, efi_edid_opt) = outputs.get_mut(output_i)?;
-
-        if let Some(efi_edid) = efi_edid_opt {
-            let edid =
-                unsafe { slice::from_raw_parts(efi_edid.0.Edid, efi_edid.0.SizeOfEdid as usize) };
-
-            if edid.len() > 0x3D {
-                return Some((
-                    (edid[0x38] as u32) | (((edid[0x3A] as u32) & 0xF0) << 4),
-                    (edid[0x3B] as u32) | (((edid[0x3D] as u32) & 0xF0) << 4),
-                ));
-            } else {
-                log::warn!("EFI EDID too small: {}", edid.len());
-            }
+        if let Some(resolution) = self
+            .video_edid(output_i)
+            .and_then(|edid| edid::preferred_resolution(&edid))
+        {
+            return Some(resolution);
         }
 
         // Fallback to the current output resolution
+        let outputs = self.outputs.borrow();
+        let (output, _efi_edid_opt) = outputs.get(output_i)?;
         Some((
             output.0.Mode.Info.HorizontalResolution,
             output.0.Mode.Info.VerticalResolution,
         ))
     }
 
+    fn video_edid(&self, output_i: usize) -> Option<Vec<u8>> {
+        let outputs = self.outputs.borrow();
+        let (_output, efi_edid_opt) = outputs.get(output_i)?;
+        let efi_edid = efi_edid_opt.as_ref()?;
+        if efi_edid.0.Edid.is_null() {
+            return None;
+        }
+        let edid =
+            unsafe { slice::from_raw_parts(efi_edid.0.Edid, efi_edid.0.SizeOfEdid as usize) };
+        Some(edid.to_vec())
+    }
+
     fn get_key(&self) -> OsKey {
         //TODO: do not unwrap
 
@@ -311,21 +435,39 @@ impl Os for OsEfi {
         ))
         .unwrap();
 
-        match key.ScanCode {
-            0 => match key.UnicodeChar {
-                8 => OsKey::Backspace,
-                13 => OsKey::Enter,
-                w => match char::from_u32(w as u32) {
-                    Some(c) => OsKey::Char(c),
-                    None => OsKey::Other,
-                },
-            },
-            1 => OsKey::Up,
-            2 => OsKey::Down,
-            3 => OsKey::Right,
-            4 => OsKey::Left,
-            8 => OsKey::Delete,
-            _ => OsKey::Other,
+        os_key(key)
+    }
+
+    fn get_key_timeout(&self, timeout_ms: u64) -> Option<OsKey> {
+        let boot_services = boot_services_full();
+        let mut timer = Event(0);
+        status_to_result((boot_services.CreateEvent)(
+            EVT_TIMER,
+            Tpl(0),
+            0,
+            0,
+            &mut timer,
+        ))
+        .ok()?;
+        // The trigger time is in units of 100 nanoseconds
+        let mut index = 1;
+        if (boot_services.SetTimer)(timer, TIMER_RELATIVE, timeout_ms.saturating_mul(10_000))
+            .is_success()
+        {
+            let events = [self.st.ConsoleIn.WaitForKey, timer];
+            let _ = (boot_services.WaitForEvent)(events.len(), events.as_ptr(), &mut index);
+        }
+        let _ = (boot_services.CloseEvent)(timer);
+
+        let mut key = TextInputKey {
+            ScanCode: 0,
+            UnicodeChar: 0,
+        };
+        if index == 0 && (self.st.ConsoleIn.ReadKeyStroke)(self.st.ConsoleIn, &mut key).is_success()
+        {
+            Some(os_key(key))
+        } else {
+            None
         }
     }
 
@@ -356,6 +498,65 @@ impl Os for OsEfi {
     }
 }
 
+/// The boot services table as laid out in the UEFI specification, including functions the uefi
+/// crate does not expose
+#[allow(non_snake_case)]
+#[repr(C)]
+struct BootServicesFull {
+    Hdr: TableHeader,
+    RaiseTpl: usize,
+    RestoreTpl: usize,
+    AllocatePages: usize,
+    FreePages: usize,
+    GetMemoryMap: usize,
+    AllocatePool: usize,
+    FreePool: usize,
+    CreateEvent: extern "efiapi" fn(
+        Kind: u32,
+        NotifyTpl: Tpl,
+        NotifyFunction: usize,
+        NotifyContext: usize,
+        Event: &mut Event,
+    ) -> Status,
+    SetTimer: extern "efiapi" fn(Event: Event, Type: u32, TriggerTime: u64) -> Status,
+    WaitForEvent:
+        extern "efiapi" fn(NumberOfEvents: usize, Event: *const Event, Index: &mut usize) -> Status,
+    SignalEvent: usize,
+    CloseEvent: extern "efiapi" fn(Event: Event) -> Status,
+    CheckEvent: usize,
+    InstallProtocolInterface: usize,
+    ReinstallProtocolInterface: usize,
+    UninstallProtocolInterface: usize,
+    HandleProtocol: usize,
+    Reserved: usize,
+    RegisterProtocolNotify: usize,
+    LocateHandle: usize,
+    LocateDevicePath: usize,
+    InstallConfigurationTable: usize,
+    LoadImage: usize,
+    StartImage: usize,
+    Exit: usize,
+    UnloadImage: extern "efiapi" fn(ImageHandle: Handle) -> Status,
+    ExitBootServices: usize,
+    GetNextMonotonicCount: usize,
+    Stall: usize,
+    SetWatchdogTimer: usize,
+    ConnectController: extern "efiapi" fn(
+        ControllerHandle: Handle,
+        DriverImageHandle: *const Handle,
+        RemainingDevicePath: usize,
+        Recursive: bool,
+    ) -> Status,
+}
+
+const EVT_TIMER: u32 = 0x8000_0000;
+const TIMER_RELATIVE: u32 = 2;
+
+fn boot_services_full() -> &'static BootServicesFull {
+    let boot_services: &BootServices = std::system_table().BootServices;
+    unsafe { &*(boot_services as *const BootServices as *const BootServicesFull) }
+}
+
 fn status_to_result(status: Status) -> Result<usize> {
     match status {
         Status(ok) if status.is_success() => Ok(ok),
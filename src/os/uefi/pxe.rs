@@ -0,0 +1,263 @@
+use alloc::vec::Vec;
+use core::{ptr, slice};
+use std::proto::Protocol;
+use uefi::{
+    Handle,
+    guid::Guid,
+    status::{Result, Status},
+};
+
+use crate::os::Os;
+use crate::progress::Progress;
+
+use super::{OsEfi, status_to_result};
+
+const PXE_BASE_CODE_PROTOCOL_GUID: Guid = Guid::parse_str("03c4e603-ac28-11d3-9a2d-0090273fc14d");
+
+// Offsets into a BOOTP/DHCPv4 packet
+const BOOTP_SIADDR: usize = 20;
+const BOOTP_FILE: usize = 108;
+const BOOTP_FILE_LEN: usize = 128;
+
+#[repr(u32)]
+#[allow(dead_code)]
+pub enum PxeBaseCodeTftpOpcode {
+    First = 0,
+    GetFileSize = 1,
+    ReadFile = 2,
+    WriteFile = 3,
+    ReadDirectory = 4,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4))]
+pub struct IpAddress(pub [u8; 16]);
+
+#[repr(C, align(4))]
+pub struct PxeBaseCodePacket(pub [u8; 1472]);
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct PxeBaseCodeMode {
+    pub Started: bool,
+    pub Ipv6Available: bool,
+    pub Ipv6Supported: bool,
+    pub UsingIpv6: bool,
+    pub BisSupported: bool,
+    pub BisDetected: bool,
+    pub AutoArp: bool,
+    pub SendGUID: bool,
+    pub DhcpDiscoverValid: bool,
+    pub DhcpAckReceived: bool,
+    pub ProxyOfferReceived: bool,
+    pub PxeDiscoverValid: bool,
+    pub PxeReplyReceived: bool,
+    pub PxeBisReplyReceived: bool,
+    pub IcmpErrorReceived: bool,
+    pub TftpErrorReceived: bool,
+    pub MakeCallbacks: bool,
+    pub TTL: u8,
+    pub ToS: u8,
+    pub StationIp: IpAddress,
+    pub SubnetMask: IpAddress,
+    pub DhcpDiscover: PxeBaseCodePacket,
+    pub DhcpAck: PxeBaseCodePacket,
+    pub ProxyOffer: PxeBaseCodePacket,
+    pub PxeDiscover: PxeBaseCodePacket,
+    pub PxeReply: PxeBaseCodePacket,
+    // Remaining fields are not used
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct PxeBaseCodeProtocol {
+    pub Revision: u64,
+    pub Start: extern "efiapi" fn(&PxeBaseCodeProtocol, UseIpv6: bool) -> Status,
+    pub Stop: extern "efiapi" fn(&PxeBaseCodeProtocol) -> Status,
+    pub Dhcp: extern "efiapi" fn(&PxeBaseCodeProtocol, SortOffers: bool) -> Status,
+    pub Discover: usize,
+    pub Mtftp: extern "efiapi" fn(
+        &PxeBaseCodeProtocol,
+        Operation: PxeBaseCodeTftpOpcode,
+        BufferPtr: *mut u8,
+        Overwrite: bool,
+        BufferSize: &mut u64,
+        BlockSize: *const usize,
+        ServerIp: &IpAddress,
+        Filename: *const u8,
+        Info: *const u8,
+        DontUseBuffer: bool,
+    ) -> Status,
+    pub UdpWrite: usize,
+    pub UdpRead: usize,
+    pub SetIpFilter: usize,
+    pub Arp: usize,
+    pub SetParameters: usize,
+    pub SetStationIp: usize,
+    pub SetPackets: usize,
+    pub Mode: &'static PxeBaseCodeMode,
+}
+
+pub struct PxeBaseCode(pub &'static mut PxeBaseCodeProtocol);
+
+impl Protocol<PxeBaseCodeProtocol> for PxeBaseCode {
+    fn guid() -> Guid {
+        PXE_BASE_CODE_PROTOCOL_GUID
+    }
+
+    fn new(inner: &'static mut PxeBaseCodeProtocol) -> Self {
+        PxeBaseCode(inner)
+    }
+}
+
+impl PxeBaseCode {
+    /// Packet with the boot server address and boot file name, preferring PXE and proxy DHCP replies
+    fn boot_packet(&self) -> Option<&[u8; 1472]> {
+        let mode = self.0.Mode;
+        if mode.PxeReplyReceived {
+            Some(&mode.PxeReply.0)
+        } else if mode.ProxyOfferReceived {
+            Some(&mode.ProxyOffer.0)
+        } else if mode.DhcpAckReceived {
+            Some(&mode.DhcpAck.0)
+        } else {
+            None
+        }
+    }
+
+    fn tftp_size(&self, server_ip: &IpAddress, filename: &[u8]) -> Result<u64> {
+        let mut size = 0;
+        status_to_result((self.0.Mtftp)(
+            self.0,
+            PxeBaseCodeTftpOpcode::GetFileSize,
+            ptr::null_mut(),
+            false,
+            &mut size,
+            ptr::null(),
+            server_ip,
+            filename.as_ptr(),
+            ptr::null(),
+            false,
+        ))?;
+        Ok(size)
+    }
+
+    fn tftp_read(&self, server_ip: &IpAddress, filename: &[u8], buffer: &mut [u8]) -> Result<u64> {
+        let mut size = buffer.len() as u64;
+        status_to_result((self.0.Mtftp)(
+            self.0,
+            PxeBaseCodeTftpOpcode::ReadFile,
+            buffer.as_mut_ptr(),
+            false,
+            &mut size,
+            ptr::null(),
+            server_ip,
+            filename.as_ptr(),
+            ptr::null(),
+            false,
+        ))?;
+        Ok(size)
+    }
+}
+
+/// Download redox-live.iso over TFTP from the server this program was netbooted from
+///
+/// The image is looked up in the same directory as the boot file.
+pub fn pxe_live_image(os: &OsEfi, handle: Handle) -> Option<&'static [u8]> {
+    let pxe = match PxeBaseCode::handle_protocol(handle) {
+        Ok(pxe) => pxe,
+        // Not booted from the network
+        Err(_) => return None,
+    };
+
+    if !pxe.0.Mode.Started || pxe.0.Mode.UsingIpv6 {
+        log::warn!("PXE base code not started with IPv4, not loading redox-live.iso");
+        return None;
+    }
+
+    let packet = match pxe.boot_packet() {
+        Some(packet) => packet,
+        None => {
+            log::warn!("No DHCP reply found, not loading redox-live.iso");
+            return None;
+        }
+    };
+
+    let mut server_ip = IpAddress([0; 16]);
+    server_ip.0[..4].copy_from_slice(&packet[BOOTP_SIADDR..BOOTP_SIADDR + 4]);
+
+    // Replace the boot file name with redox-live.iso, keeping its directory
+    let boot_file = &packet[BOOTP_FILE..BOOTP_FILE + BOOTP_FILE_LEN];
+    let boot_file_len = boot_file
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(boot_file.len());
+    let dir_len = boot_file[..boot_file_len]
+        .iter()
+        .rposition(|&b| b == b'/' || b == b'\\')
+        .map_or(0, |i| i + 1);
+    let mut filename = Vec::with_capacity(dir_len + 15);
+    filename.extend_from_slice(&boot_file[..dir_len]);
+    filename.extend_from_slice(b"redox-live.iso\0");
+    let name = core::str::from_utf8(&filename[..filename.len() - 1]).unwrap_or("redox-live.iso");
+
+    let [a, b, c, d, ..] = server_ip.0;
+    let size = match pxe.tftp_size(&server_ip, &filename) {
+        Ok(size) => size,
+        Err(Status::NOT_FOUND) | Err(Status::TFTP_ERROR) => {
+            log::debug!("No {} on TFTP server {}.{}.{}.{}", name, a, b, c, d);
+            return None;
+        }
+        Err(err) => {
+            log::warn!(
+                "Failed to get size of {} on TFTP server {}.{}.{}.{}: {:?}",
+                name,
+                a,
+                b,
+                c,
+                d,
+                err
+            );
+            return None;
+        }
+    };
+    if size == 0 {
+        log::warn!("{} on TFTP server {}.{}.{}.{} is empty", name, a, b, c, d);
+        return None;
+    }
+
+    let ptr = os.alloc_zeroed_page_aligned(size as usize);
+    if ptr.is_null() {
+        log::warn!("Failed to allocate memory for {}", name);
+        return None;
+    }
+    let buffer = unsafe { slice::from_raw_parts_mut(ptr, size as usize) };
+
+    // The firmware downloads the whole file in one call, so only the result is shown
+    let progress = Progress::new(os, name, size);
+    match pxe.tftp_read(&server_ip, &filename, buffer) {
+        Ok(read) if read == size => {
+            progress.finish(size, size);
+        }
+        Ok(read) => {
+            println!();
+            log::warn!("{} ended at {} of {} bytes", name, read, size);
+            return None;
+        }
+        Err(err) => {
+            println!();
+            log::warn!(
+                "Failed to read {} from TFTP server {}.{}.{}.{}: {:?}",
+                name,
+                a,
+                b,
+                c,
+                d,
+                err
+            );
+            return None;
+        }
+    }
+
+    Some(buffer)
+}
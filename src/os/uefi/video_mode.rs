@@ -1,10 +1,34 @@
 use core::ptr;
 use log::error;
+use uefi::graphics::{GraphicsOutputModeInfo, GraphicsPixelFormat};
 use uefi::status::Status;
 
 use crate::os::OsVideoMode;
 use crate::os::uefi::display::Output;
 
+/// Bits per pixel and red, green and blue masks of a mode with a frame buffer
+fn pixel_format(info: &GraphicsOutputModeInfo) -> Option<(u32, [u32; 3])> {
+    match info.PixelFormat {
+        GraphicsPixelFormat::PixelRedGreenBlueReserved8BitPerColor => {
+            Some((32, [0x0000_00FF, 0x0000_FF00, 0x00FF_0000]))
+        }
+        GraphicsPixelFormat::PixelBlueGreenRedReserved8BitPerColor => {
+            Some((32, OsVideoMode::BGRX_MASKS))
+        }
+        GraphicsPixelFormat::PixelBitMask => {
+            let masks = info.PixelInformation;
+            let used = masks.RedMask | masks.GreenMask | masks.BlueMask | masks.ReservedMask;
+            let bpp = (32 - used.leading_zeros()).next_multiple_of(8);
+            if bpp != 16 && bpp != 32 {
+                error!("Unsupported {} bits per pixel mode", bpp);
+                return None;
+            }
+            Some((bpp, [masks.RedMask, masks.GreenMask, masks.BlueMask]))
+        }
+        _ => None,
+    }
+}
+
 pub struct VideoModeIter {
     output_opt: Option<Output>,
     i: u32,
@@ -40,6 +64,10 @@ impl Iterator for VideoModeIter {
                 let width = mode.HorizontalResolution;
                 let height = mode.VerticalResolution;
                 let stride = mode.PixelsPerScanLine;
+                let Some((bpp, masks)) = pixel_format(&mode) else {
+                    // No frame buffer
+                    continue;
+                };
 
                 return Some(OsVideoMode {
                     id,
@@ -48,6 +76,10 @@ impl Iterator for VideoModeIter {
                     stride,
                     // Base is retrieved later by setting the mode
                     base: 0,
+                    bpp,
+                    red_mask: masks[0],
+                    green_mask: masks[1],
+                    blue_mask: masks[2],
                 });
             }
         }
@@ -0,0 +1,73 @@
+use alloc::string::String;
+use core::cell::Cell;
+
+use crate::MIBI;
+use crate::os::Os;
+
+const BAR_WIDTH: u64 = 20;
+
+/// Progress display for loading large amounts of data
+pub struct Progress<'a, O: Os> {
+    os: &'a O,
+    name: &'a str,
+    total: u64,
+    start_opt: Option<u64>,
+    last_mib: Cell<u64>,
+}
+
+impl<'a, O: Os> Progress<'a, O> {
+    pub fn new(os: &'a O, name: &'a str, total: u64) -> Self {
+        let progress = Self {
+            os,
+            name,
+            total,
+            start_opt: os.time_ms(),
+            last_mib: Cell::new(0),
+        };
+        progress.print(0, 0);
+        progress
+    }
+
+    /// Update with the amount of data processed and the amount of data actually read
+    pub fn update(&self, done: u64, read: u64) {
+        // Printing is slow on some consoles, so only print once per MiB
+        if done / MIBI as u64 != self.last_mib.get() {
+            self.last_mib.set(done / MIBI as u64);
+            self.print(done, read);
+        }
+    }
+
+    pub fn finish(&self, done: u64, read: u64) {
+        self.print(done, read);
+        println!();
+    }
+
+    fn print(&self, done: u64, read: u64) {
+        let filled = (done.min(self.total) * BAR_WIDTH)
+            .checked_div(self.total)
+            .unwrap_or(BAR_WIDTH);
+
+        let mut bar = String::with_capacity(BAR_WIDTH as usize);
+        for i in 0..BAR_WIDTH {
+            bar.push(if i < filled { '=' } else { ' ' });
+        }
+
+        print!(
+            "\r{}: [{}] {}/{} MiB",
+            self.name,
+            bar,
+            done / MIBI as u64,
+            self.total / MIBI as u64
+        );
+
+        if read != done {
+            print!(", {} MiB read", read / MIBI as u64);
+        }
+
+        if let (Some(start), Some(now)) = (self.start_opt, self.os.time_ms())
+            && let Some(rate) = (read * 1000).checked_div(now.saturating_sub(start))
+        {
+            print!(", {} MiB/s", rate / MIBI as u64);
+        }
+    }
+}